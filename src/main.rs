@@ -2,119 +2,619 @@ mod scene;
 mod tracer;
 mod utils;
 
-use std::{env, f32::INFINITY};
+use std::{
+    path::Path,
+    process,
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
+};
 
 use indicatif::{ProgressBar, ProgressStyle};
-use scene::scene::Scene;
+use scene::{
+    light::{cosine_weighted_direction, diffuse_albedo},
+    material::Fresnel,
+    scene::Scene,
+};
 use tracer::{
     hittable::{HitRecord, Hittable},
     ray::Ray,
 };
 use utils::{
-    color_utility, file_loader, png_creator,
+    aov::Aov,
+    cli_arguments::{CliArguments, Integrator},
+    color_utility, file_loader,
+    hdr_creator::{self, OutputFormat},
+    png_creator,
     vec3::{Color, Vec3},
 };
 
 use rand::distributions::Distribution;
 use rand::distributions::Uniform;
+use rand::{rngs::StdRng, Rng, RngCore, SeedableRng};
 
-fn ray_color(ray: &Ray, scene: &Scene, current_bounce_number: usize) -> Color {
+fn ray_color(
+    ray: &Ray,
+    scene: &Scene,
+    current_bounce_number: usize,
+    rng: &mut dyn RngCore,
+) -> Color {
     let mut hit_record = HitRecord::new();
-    let mut color = Color::new();
     let mut reflected_color = Color::new();
     let mut refracted_color = Color::new();
 
-    if scene.surfaces.hit(ray, 0.0, INFINITY, &mut hit_record) {
-        color = scene
+    if scene.surfaces.hit(ray, 0.0, f32::INFINITY, &mut hit_record) {
+        hit_record.texture_derivatives = texture_derivatives(ray, &hit_record);
+        let color = scene
             .lights
-            .calculate_final_color(&ray, &hit_record, &scene.surfaces);
+            .calculate_final_color(ray, &hit_record, &scene.surfaces, rng);
+        let absorption = absorption_along_ray(ray, &hit_record);
 
         if current_bounce_number > scene.camera.max_bounces {
-            return color;
+            return &absorption * &color;
         }
 
-        if hit_record.material.get_reflectance().r > 0.00001 {
-            let bias = 0.001; // Small bias value to mitigate surface acne
-            let reflected_direction = reflect(&ray.direction, &hit_record.normal);
-            let reflected_origin = &hit_record.point + &(&bias * &reflected_direction); // Apply bias to the origin
-            let reflected_ray = Ray {
-                origin: reflected_origin,
-                direction: reflected_direction,
-            };
+        let (reflectance, transmittance) = specular_weights(ray, &hit_record);
 
-            reflected_color = &hit_record.material.get_reflectance().r
-                * &ray_color(&reflected_ray, scene, current_bounce_number + 1);
+        if reflectance > 0.00001 {
+            reflected_color = &reflectance
+                * &ray_color(
+                    &reflected_ray(ray, &hit_record),
+                    scene,
+                    current_bounce_number + 1,
+                    rng,
+                );
         }
 
-        if hit_record.material.get_transmittance().t > 0.00001 {
-            let bias = 0.001; // Small bias value to mitigate surface acne
-            let refracted_direction = refract(&ray.direction, &hit_record.normal, &hit_record);
-            let refracted_origin = &hit_record.point + &(&bias * &refracted_direction); // Apply bias to the origin
-            let refracted_ray = Ray {
-                origin: refracted_origin,
-                direction: refracted_direction,
-            };
+        if transmittance > 0.00001 {
+            refracted_color = &transmittance
+                * &ray_color(
+                    &refracted_ray(ray, &hit_record),
+                    scene,
+                    current_bounce_number + 1,
+                    rng,
+                );
+        }
+
+        return &absorption
+            * &(&(&color * &(1.0 - reflectance - transmittance))
+                + &(&reflected_color + &refracted_color));
+    }
+
+    scene.background(ray)
+}
+
+// Russian roulette only starts after a few bounces, the first bounces contribute the most
+const RUSSIAN_ROULETTE_START_BOUNCE: usize = 3;
+
+/// Path tracing with next event estimation: the lights are sampled at every hit and the path
+/// continues in a single direction, chosen between the mirror reflection, the refraction and a
+/// cosine weighted diffuse bounce. Emitters sampled as area lights and the lighting environment
+/// are only counted when hit directly or through mirrors and refractions, diffuse bounces
+/// already sampled them.
+/// Ambient lights are ignored, the diffuse bounces replace them.
+fn path_color(
+    ray: &Ray,
+    scene: &Scene,
+    current_bounce_number: usize,
+    count_emission: bool,
+    rng: &mut dyn RngCore,
+) -> Color {
+    let mut hit_record = HitRecord::new();
+
+    if !scene.surfaces.hit(ray, 0.0, f32::INFINITY, &mut hit_record) {
+        return if count_emission || !scene.lights.samples_environment() {
+            scene.background(ray)
+        } else {
+            Color::new()
+        };
+    }
+    hit_record.texture_derivatives = texture_derivatives(ray, &hit_record);
+
+    let mut color = scene
+        .lights
+        .calculate_direct_light(ray, &hit_record, &scene.surfaces, rng);
+    if count_emission || !scene.lights.samples_emission_of(&hit_record) {
+        if let Some(emission) = hit_record.material.get_emission() {
+            color += &emission.color;
+        }
+    }
+    let absorption = absorption_along_ray(ray, &hit_record);
+
+    let (mut reflectance, mut transmittance) = specular_weights(ray, &hit_record);
+    // Weights above 1 in total would reflect more light than arrives
+    let specular_weight = reflectance + transmittance;
+    if specular_weight > 1.0 {
+        reflectance /= specular_weight;
+        transmittance /= specular_weight;
+    }
+    let diffuse_weight = (1.0 - reflectance - transmittance).max(0.0);
+    color *= &diffuse_weight;
+
+    if current_bounce_number > scene.camera.max_bounces {
+        return &absorption * &color;
+    }
+
+    // The event is chosen proportional to its weight, which cancels out except for the albedo
+    let event = rng.gen::<f32>() * (reflectance + transmittance + diffuse_weight);
+    let (next_ray, mut throughput, is_diffuse) = if event < reflectance {
+        (
+            reflected_ray(ray, &hit_record),
+            Color::from_values(1.0, 1.0, 1.0),
+            false,
+        )
+    } else if event < reflectance + transmittance {
+        (
+            refracted_ray(ray, &hit_record),
+            Color::from_values(1.0, 1.0, 1.0),
+            false,
+        )
+    } else {
+        (
+            diffuse_ray(ray, &hit_record, rng),
+            diffuse_albedo(&hit_record),
+            true,
+        )
+    };
+
+    if current_bounce_number >= RUSSIAN_ROULETTE_START_BOUNCE {
+        let survival_probability = throughput
+            .r()
+            .max(throughput.g())
+            .max(throughput.b())
+            .clamp(0.05, 1.0);
+        if rng.gen::<f32>() >= survival_probability {
+            return &absorption * &color;
+        }
+        throughput /= &survival_probability;
+    }
+
+    let indirect_color = path_color(
+        &next_ray,
+        scene,
+        current_bounce_number + 1,
+        !is_diffuse,
+        rng,
+    );
+    &absorption * &(&color + &(&throughput * &indirect_color))
+}
+
+/// Clay render, surfaces are white and only darkened by ambient occlusion
+fn occlusion_color(ray: &Ray, scene: &Scene, rng: &mut dyn RngCore) -> Color {
+    let mut hit_record = HitRecord::new();
+
+    if !scene.surfaces.hit(ray, 0.0, f32::INFINITY, &mut hit_record) {
+        return scene.background(ray);
+    }
 
-            refracted_color = &hit_record.material.get_transmittance().t
-                * &ray_color(&refracted_ray, scene, current_bounce_number + 1);
+    let visibility = scene
+        .lights
+        .ambient_occlusion()
+        .visibility(&hit_record, &scene.surfaces, rng);
+    Color::from_values(visibility, visibility, visibility)
+}
+
+// Weights of the mirror reflection and the refraction at the hit point
+fn specular_weights(ray: &Ray, hit_record: &HitRecord) -> (f32, f32) {
+    let reflectance = hit_record.material.get_reflectance().r;
+    let transmittance = hit_record.material.get_transmittance().t;
+
+    // Dielectrics split the combined weight of reflection and refraction by the Fresnel term
+    if let Some(fresnel) = &hit_record.material.get_refraction().fresnel {
+        let fresnel_reflectance =
+            fresnel_reflectance(&ray.direction, &hit_record.normal, hit_record, fresnel);
+        let specular_weight = reflectance + transmittance;
+        return (
+            specular_weight * fresnel_reflectance,
+            specular_weight * (1.0 - fresnel_reflectance),
+        );
+    }
+
+    (reflectance, transmittance)
+}
+
+fn reflected_ray(ray: &Ray, hit_record: &HitRecord) -> Ray {
+    let bias = 0.001; // Small bias value to mitigate surface acne
+    let reflected_direction = reflect(&ray.direction, &hit_record.normal);
+    let reflected_origin = &hit_record.point + &(&bias * &reflected_direction); // Apply bias to the origin
+    Ray::from_values(&reflected_origin, &reflected_direction)
+}
+
+fn refracted_ray(ray: &Ray, hit_record: &HitRecord) -> Ray {
+    let bias = 0.001; // Small bias value to mitigate surface acne
+    let refracted_direction = refract(&ray.direction, &hit_record.normal, hit_record)
+        // Without Fresnel, total internal reflection falls back to a mirror direction
+        .unwrap_or_else(|| reflect(&ray.direction, &hit_record.normal));
+    let refracted_origin = &hit_record.point + &(&bias * &refracted_direction); // Apply bias to the origin
+    Ray::from_values(&refracted_origin, &refracted_direction)
+}
+
+// Cosine weighted direction on the side of the surface the ray came from
+fn diffuse_ray(ray: &Ray, hit_record: &HitRecord, rng: &mut dyn RngCore) -> Ray {
+    let bias = 0.001; // Small bias value to mitigate surface acne
+    let normal = hit_record.normal.unit_vector();
+    let normal = if ray.direction.dot(&normal) > 0.0 {
+        -&normal
+    } else {
+        normal
+    };
+
+    Ray::from_values(
+        &(&hit_record.point + &(&bias * &normal)),
+        &cosine_weighted_direction(&normal, rng),
+    )
+}
+
+/// Change of the texture coordinate towards the neighbouring pixels. The differentials of
+/// camera rays are intersected with the tangent plane at the hit point and the offsets are
+/// expressed in the texture tangents of the surface. Only mipmapped textures need it.
+fn texture_derivatives(ray: &Ray, hit_record: &HitRecord) -> Option<(Vec3, Vec3)> {
+    let differentials = ray.differentials.as_ref()?;
+    let texture = hit_record.material.get_texture_information()?;
+    if !texture.filter.uses_mipmaps() {
+        return None;
+    }
+    let (u_tangent, v_tangent) = hit_record.texture_tangents?;
+
+    // Least squares solution of u_tangent * du + v_tangent * dv = offset, the tangents don't
+    // have to be orthogonal
+    let uu = u_tangent.dot(&u_tangent);
+    let uv = u_tangent.dot(&v_tangent);
+    let vv = v_tangent.dot(&v_tangent);
+    let determinant = uu * vv - uv * uv;
+    if determinant <= 0.000001 * uu * vv {
+        return None;
+    }
+
+    let derivative = |neighbour_ray: Ray| {
+        let t = hit_record
+            .normal
+            .dot(&(&hit_record.point - &neighbour_ray.origin))
+            / hit_record.normal.dot(&neighbour_ray.direction);
+        if !t.is_finite() {
+            return None;
         }
+        let offset = &neighbour_ray.at(t) - &hit_record.point;
+        let u_offset = u_tangent.dot(&offset);
+        let v_offset = v_tangent.dot(&offset);
+        Some(Vec3::from_values(
+            (vv * u_offset - uv * v_offset) / determinant,
+            (uu * v_offset - uv * u_offset) / determinant,
+            0.0,
+        ))
+    };
 
-        return &(&color
-            * &(1.0
-                - hit_record.material.get_reflectance().r
-                - hit_record.material.get_transmittance().t))
-            + &(&reflected_color + &refracted_color);
+    match (
+        derivative(differentials.x_ray()),
+        derivative(differentials.y_ray()),
+    ) {
+        (Some(x_derivative), Some(y_derivative)) => Some((x_derivative, y_derivative)),
+        (Some(derivative), None) | (None, Some(derivative)) => Some((derivative, derivative)),
+        (None, None) => None,
     }
+}
 
-    scene.background_color
+/// Beer-Lambert attenuation of a ray which travelled through an absorbing object and hits its
+/// surface from the inside, the outward normal points along the ray in that case
+fn absorption_along_ray(ray: &Ray, hit_record: &HitRecord) -> Color {
+    match hit_record.material.get_absorption() {
+        Some(absorption) if ray.direction.dot(&hit_record.normal) > 0.0 => {
+            let distance = hit_record.t * ray.direction.length();
+            Color::from_values(
+                (-absorption.r() * distance).exp(),
+                (-absorption.g() * distance).exp(),
+                (-absorption.b() * distance).exp(),
+            )
+        }
+        _ => Color::from_values(1.0, 1.0, 1.0),
+    }
 }
 
 fn reflect(incident: &Vec3, normal: &Vec3) -> Vec3 {
     incident - &(&(2.0 * incident.dot(normal)) * normal)
 }
 
-fn refract(incident: &Vec3, normal: &Vec3, hit_record: &HitRecord) -> Vec3 {
+// Cosine of the incidence angle, ratio of the refraction indices and the normal on the side
+// of the incident ray, the ray enters the object if it hits the front of the surface
+fn incidence(incident: &Vec3, normal: &Vec3, hit_record: &HitRecord) -> (f32, f32, Vec3) {
     let incident_normalized = incident.unit_vector();
-    let mut normal_normalized = normal.unit_vector();
+    let normal_normalized = normal.unit_vector();
 
-    let mut cosine = incident_normalized.dot(&normal_normalized);
-    cosine = cosine.clamp(-1.0, 1.0);
+    let cosine = incident_normalized.dot(&normal_normalized).clamp(-1.0, 1.0);
 
-    let eta = if cosine < 0.0 {
-        cosine = -cosine;
-        1.0 / hit_record.material.get_refraction().iof
+    if cosine < 0.0 {
+        (
+            -cosine,
+            1.0 / hit_record.material.get_refraction().iof,
+            normal_normalized,
+        )
     } else {
-        normal_normalized = -&normal_normalized;
-        hit_record.material.get_refraction().iof
-    };
+        (
+            cosine,
+            hit_record.material.get_refraction().iof,
+            -&normal_normalized,
+        )
+    }
+}
+
+/// Refracted direction, `None` in case of total internal reflection
+fn refract(incident: &Vec3, normal: &Vec3, hit_record: &HitRecord) -> Option<Vec3> {
+    let incident_normalized = incident.unit_vector();
+    let (cosine, eta, normal_normalized) = incidence(incident, normal, hit_record);
 
     let pre_sqrt_check = 1.0 - eta.powi(2) * (1.0 - cosine.powi(2));
 
     if pre_sqrt_check < 0.0 {
-        return reflect(&incident, &normal);
+        return None;
     }
 
     /*(&(&eta * &(&incident_normalized + &(&normal_normalized * &cosine)))
     - &(&normal_normalized * &f32::sqrt(pre_sqrt_check))).unit_vector()*/
 
-    &(&eta * &incident_normalized)
-        + &(&(eta * cosine - f32::sqrt(pre_sqrt_check)) * &normal_normalized)
+    Some(
+        &(&eta * &incident_normalized)
+            + &(&(eta * cosine - f32::sqrt(pre_sqrt_check)) * &normal_normalized),
+    )
 }
 
-fn main() {
-    let mut sample_size: Option<usize> = None;
+/// Fraction of the light which is reflected at a dielectric boundary, one for total internal reflection
+fn fresnel_reflectance(
+    incident: &Vec3,
+    normal: &Vec3,
+    hit_record: &HitRecord,
+    fresnel: &Fresnel,
+) -> f32 {
+    let (cosine, eta, _) = incidence(incident, normal, hit_record);
+
+    let sine_transmitted_squared = eta.powi(2) * (1.0 - cosine.powi(2));
+    if sine_transmitted_squared >= 1.0 {
+        return 1.0;
+    }
+    let cosine_transmitted = (1.0 - sine_transmitted_squared).sqrt();
 
-    for arg in env::args() {
-        if let Some(value) = arg.strip_prefix("-s=") {
-            if let Ok(size) = value.parse::<usize>() {
-                sample_size = Some(size);
+    match fresnel {
+        Fresnel::Exact => {
+            let r_s = (eta * cosine - cosine_transmitted) / (eta * cosine + cosine_transmitted);
+            let r_p = (eta * cosine_transmitted - cosine) / (eta * cosine_transmitted + cosine);
+            (r_s.powi(2) + r_p.powi(2)) / 2.0
+        }
+        Fresnel::Schlick => {
+            let r_0 = ((1.0 - eta) / (1.0 + eta)).powi(2);
+            // Inside the denser medium the angle of the transmitted ray has to be used
+            let cosine = if eta > 1.0 {
+                cosine_transmitted
+            } else {
+                cosine
+            };
+            r_0 + (1.0 - r_0) * (1.0 - cosine).powi(5)
+        }
+    }
+}
+
+// Pixels without a camera ray (outside of a fisheye image circle) stay black
+fn camera_ray_color(
+    ray: Option<&Ray>,
+    scene: &Scene,
+    integrator: Integrator,
+    rng: &mut dyn RngCore,
+) -> Color {
+    match (ray, integrator) {
+        (Some(ray), Integrator::Whitted) => ray_color(ray, scene, 0, rng),
+        (Some(ray), Integrator::Path) => path_color(ray, scene, 0, true, rng),
+        (Some(ray), Integrator::AmbientOcclusion) => occlusion_color(ray, scene, rng),
+        (None, _) => Color::new(),
+    }
+}
+
+const TILE_SIZE: usize = 32;
+
+struct Tile {
+    column_start: usize,
+    column_end: usize,
+    row_start: usize,
+    row_end: usize,
+}
+
+// Every pixel gets its own generator derived from the seed and its index,
+// so the result doesn't depend on which thread rendered which tile
+fn pixel_rng(seed: u64, pixel_index: usize) -> StdRng {
+    StdRng::seed_from_u64(seed ^ (pixel_index as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15))
+}
+
+fn render_tile(scene: &Scene, tile: &Tile, arguments: &CliArguments, seed: u64) -> Vec<Color> {
+    let uniform_sampler = Uniform::from(-0.5..=0.5);
+    let mut tile_colors =
+        Vec::with_capacity((tile.row_end - tile.row_start) * (tile.column_end - tile.column_start));
+
+    for row in tile.row_start..tile.row_end {
+        // Rows are stored top to bottom while j starts at the bottom of the image
+        let j = scene.camera.resolution_vertical - 1 - row;
+        for i in tile.column_start..tile.column_end {
+            let mut rng = pixel_rng(seed, row * scene.camera.resolution_horizontal + i);
+            let color = if let Some(sample_size) = arguments.sample_size {
+                // The samples of a pixel are closer together than the pixels
+                let spacing = (1.0 / (sample_size as f64).sqrt()).max(0.125);
+                let mut color = Color::new();
+                for _ in 0..sample_size {
+                    let x = i as f64 + uniform_sampler.sample(&mut rng);
+                    let y = j as f64 + uniform_sampler.sample(&mut rng);
+                    let ray = scene.camera.construct_lens_ray(x, y, &mut rng).map(|ray| {
+                        let differentials =
+                            scene.camera.construct_differentials(&ray, x, y, spacing);
+                        ray.with_differentials(differentials)
+                    });
+                    color += &camera_ray_color(ray.as_ref(), scene, arguments.integrator, &mut rng);
+                }
+                &color / &(sample_size as f32)
+            } else {
+                let (x, y) = (i as f64, j as f64);
+                let ray = scene.camera.construct_ray(x, y).map(|ray| {
+                    let differentials = scene.camera.construct_differentials(&ray, x, y, 1.0);
+                    ray.with_differentials(differentials)
+                });
+                camera_ray_color(ray.as_ref(), scene, arguments.integrator, &mut rng)
+            };
+            tile_colors.push(color);
+        }
+    }
+
+    tile_colors
+}
+
+/// Renders the image tile by tile on all threads, returns the colors row by row from the top
+fn render(scene: &Scene, arguments: &CliArguments, progress_bar: &ProgressBar) -> Vec<Color> {
+    let width = scene.camera.resolution_horizontal;
+    let height = scene.camera.resolution_vertical;
+    let seed = arguments.seed.unwrap_or_else(|| rand::thread_rng().gen());
+
+    let mut tiles = vec![];
+    for row_start in (0..height).step_by(TILE_SIZE) {
+        for column_start in (0..width).step_by(TILE_SIZE) {
+            tiles.push(Tile {
+                column_start,
+                column_end: (column_start + TILE_SIZE).min(width),
+                row_start,
+                row_end: (row_start + TILE_SIZE).min(height),
+            });
+        }
+    }
+
+    let next_tile = AtomicUsize::new(0);
+    let rendered_tiles: Vec<(usize, Vec<Color>)> = thread::scope(|scope| {
+        let workers: Vec<_> = (0..arguments.thread_count)
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut finished_tiles = vec![];
+                    loop {
+                        let tile_index = next_tile.fetch_add(1, Ordering::Relaxed);
+                        let Some(tile) = tiles.get(tile_index) else {
+                            break;
+                        };
+                        let tile_colors = render_tile(scene, tile, arguments, seed);
+                        progress_bar.inc(tile_colors.len() as u64);
+                        finished_tiles.push((tile_index, tile_colors));
+                    }
+                    finished_tiles
+                })
+            })
+            .collect();
+
+        workers
+            .into_iter()
+            .flat_map(|worker| worker.join().expect("Render thread panicked"))
+            .collect()
+    });
+
+    let mut framebuffer = vec![Color::new(); width * height];
+    for (tile_index, tile_colors) in rendered_tiles {
+        let tile = &tiles[tile_index];
+        let tile_width = tile.column_end - tile.column_start;
+        for (offset, color) in tile_colors.into_iter().enumerate() {
+            let row = tile.row_start + offset / tile_width;
+            let column = tile.column_start + offset % tile_width;
+            framebuffer[row * width + column] = color;
+        }
+    }
+
+    framebuffer
+}
+
+/// Captures the AOVs from the rays through the pixel centers, returns one image per AOV
+/// with the values row by row from the top
+fn render_aovs(scene: &Scene, aovs: &[Aov]) -> Vec<Vec<Color>> {
+    let width = scene.camera.resolution_horizontal;
+    let height = scene.camera.resolution_vertical;
+    let mut aov_images = vec![Vec::with_capacity(width * height); aovs.len()];
+
+    for row in 0..height {
+        let j = height - 1 - row;
+        for i in 0..width {
+            let mut hit_record = HitRecord::new();
+            let hit = scene
+                .camera
+                .construct_ray(i as f64, j as f64)
+                .filter(|ray| scene.surfaces.hit(ray, 0.0, f32::INFINITY, &mut hit_record))
+                .map(|_| &hit_record);
+
+            for (aov, image) in aovs.iter().zip(&mut aov_images) {
+                image.push(aov.value(hit, scene));
             }
         }
     }
 
-    let scene = file_loader::load_and_deserialize_scene();
+    aov_images
+}
 
-    let mut image_data = vec![];
+/// Writes the colors row by row from the top, additional images get a suffix,
+/// e.g. `example1_depth.png` for `depth`. PNG files store colors as sRGB,
+/// other values (`is_color` false) are quantized without encoding them.
+fn write_image(
+    colors: &[Color],
+    scene: &Scene,
+    format: OutputFormat,
+    suffix: Option<&str>,
+    is_color: bool,
+) {
+    let file_name = output_file_name(&scene.output_file, format, suffix);
+
+    match format {
+        OutputFormat::Png => {
+            let mut image_data = vec![];
+            for color in colors {
+                if is_color {
+                    color_utility::to_png_color(color, &mut image_data, 1.0);
+                } else {
+                    color_utility::to_png_data(color, &mut image_data);
+                }
+            }
+            png_creator::create_png_at_path(&image_data, scene, &file_name);
+        }
+        OutputFormat::OpenExr => hdr_creator::create_exr_at_path(colors, scene, &file_name),
+        OutputFormat::RadianceHdr => {
+            hdr_creator::create_radiance_hdr_at_path(colors, scene, &file_name)
+        }
+        OutputFormat::Pfm => hdr_creator::create_pfm_at_path(colors, scene, &file_name),
+    }
+}
+
+// The output file of the scene is kept as is if it already has the right extension
+fn output_file_name(output_file: &str, format: OutputFormat, suffix: Option<&str>) -> String {
+    let path = Path::new(output_file);
+    let has_extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| extension.eq_ignore_ascii_case(format.extension()));
+
+    match suffix {
+        None if has_extension => output_file.to_string(),
+        _ => {
+            let stem = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .unwrap_or("output");
+            match suffix {
+                Some(suffix) => format!("{}_{}.{}", stem, suffix, format.extension()),
+                None => format!("{}.{}", stem, format.extension()),
+            }
+        }
+    }
+}
+
+fn main() {
+    let arguments = CliArguments::parse();
+
+    let mut scene = match file_loader::load_and_deserialize_scene() {
+        Ok(scene) => scene,
+        Err(err) => {
+            eprintln!("{}", err);
+            process::exit(1);
+        }
+    };
+    if let Some(operator) = arguments.tone_map_operator {
+        scene.tone_mapping.operator = operator;
+    }
+    if let Some(exposure) = arguments.exposure {
+        scene.tone_mapping.exposure = exposure;
+    }
 
     let progress_bar = ProgressBar::new(
         (scene.camera.resolution_horizontal * scene.camera.resolution_vertical)
@@ -127,37 +627,78 @@ fn main() {
             .expect("Failed to create progress style for progress bar"),
     );
 
-    if sample_size.is_none() {
-        for j in (0..scene.camera.resolution_vertical).rev() {
-            for i in 0..scene.camera.resolution_horizontal {
-                let ray = scene.camera.construct_ray(i as f64, j as f64);
-                let color: Color = ray_color(&ray, &scene, 0);
-                color_utility::to_png_color(&color, &mut image_data, 1.0);
-                progress_bar.inc(1);
-            }
-        }
-    } else {
-        let mut rng = rand::thread_rng();
-        let uniform_sampler = Uniform::from(-0.5..=0.5);
+    let framebuffer = render(&scene, &arguments, &progress_bar);
 
-        for j in (0..scene.camera.resolution_vertical).rev() {
-            for i in 0..scene.camera.resolution_horizontal {
-                let mut color = Color::new();
-                for _ in 0..sample_size.unwrap() {
-                    let ray = scene.camera.construct_ray(
-                        i as f64 + uniform_sampler.sample(&mut rng),
-                        j as f64 + uniform_sampler.sample(&mut rng),
-                    );
-                    color += &ray_color(&ray, &scene, 0);
-                }
-                color_utility::to_png_color(
-                    &color,
-                    &mut image_data,
-                    sample_size.unwrap() as f32,
-                );
-                progress_bar.inc(1);
-            }
-        }
+    let output_format = arguments
+        .output_format
+        .unwrap_or_else(|| OutputFormat::from_file_name(&scene.output_file));
+    // Floating point formats store the radiance as rendered
+    let colors = match output_format {
+        OutputFormat::Png => framebuffer
+            .iter()
+            .map(|color| scene.tone_mapping.apply(color))
+            .collect(),
+        _ => framebuffer,
+    };
+    write_image(&colors, &scene, output_format, None, true);
+
+    for (aov, values) in arguments
+        .aovs
+        .iter()
+        .zip(render_aovs(&scene, &arguments.aovs))
+    {
+        // Floating point formats keep the raw values, e.g. the depth in scene units
+        let colors = match output_format {
+            OutputFormat::Png => aov.display_colors(&values),
+            _ => values,
+        };
+        write_image(
+            &colors,
+            &scene,
+            output_format,
+            Some(aov.name()),
+            aov.is_color(),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn output_file_name_keeps_a_matching_extension() {
+        assert_eq!(
+            output_file_name("example1.png", OutputFormat::Png, None),
+            "example1.png"
+        );
+        assert_eq!(
+            output_file_name("example1.PNG", OutputFormat::Png, None),
+            "example1.PNG"
+        );
+    }
+
+    #[test]
+    fn output_file_name_replaces_other_extensions() {
+        assert_eq!(
+            output_file_name("example1.png", OutputFormat::OpenExr, None),
+            "example1.exr"
+        );
+        assert_eq!(
+            output_file_name("example1", OutputFormat::Pfm, None),
+            "example1.pfm"
+        );
+    }
+
+    #[test]
+    fn output_file_name_with_suffix() {
+        assert_eq!(
+            output_file_name("example1.png", OutputFormat::Png, Some("depth")),
+            "example1_depth.png"
+        );
+        assert_eq!(
+            output_file_name("example1.png", OutputFormat::RadianceHdr, Some("normal")),
+            "example1_normal.hdr"
+        );
     }
-    png_creator::create_png_at_path(&image_data, &scene);
 }
@@ -1,7 +1,8 @@
-use serde::Deserialize;
+use rand::Rng;
+use serde::{de::Error, Deserialize};
 
 use crate::{
-    tracer::ray::Ray,
+    tracer::ray::{Ray, RayDifferentials},
     utils::{
         deserialization_helpers::{deserialize_point, deserialize_vector},
         mat4::Mat4,
@@ -9,51 +10,61 @@ use crate::{
     },
 };
 
+#[derive(Clone, Copy)]
+pub enum Projection {
+    Perspective,
+    // Parallel rays, the view width is given in world units
+    Orthographic { view_width: f32 },
+    // Full 360° x 180° panorama, horizontal_fov is ignored
+    Equirectangular,
+    // Equidistant circular fisheye, horizontal_fov is the angle between the view direction
+    // and the border of the circle, i.e. half of the angle covered like for perspective
+    Fisheye,
+}
+
 pub struct Camera {
     pub position: Point,
     pub lookat: Vec3,
-    pub up: Vec3,
     pub horizontal_fov: f32,
     pub resolution_horizontal: usize,
     pub resolution_vertical: usize,
     pub max_bounces: usize,
+    pub projection: Projection,
+    // Thin lens, a radius of zero is a pinhole camera
+    pub aperture_radius: f32,
+    pub focal_distance: f32,
 
-    pub horizontal: Vec3,
-    pub vertical: Vec3,
-    pub lower_left_corner: Point,
     pub transformation_matrix: Mat4,
 }
 
+/// Settings of the camera apart from its placement in the scene
+pub struct CameraOptions {
+    pub horizontal_fov: f32,
+    pub resolution_horizontal: usize,
+    pub resolution_vertical: usize,
+    pub max_bounces: usize,
+    pub projection: Projection,
+    pub aperture_radius: f32,
+    pub focal_distance: f32,
+}
+
 impl Camera {
     pub fn from_values(
         position: Point,
         lookat: Vec3,
-        up: Vec3,
-        horizontal_fov: f32,
-        resolution_horizontal: usize,
-        resolution_vertical: usize,
-        max_bounces: usize,
         transformation_matrix: Mat4,
+        options: CameraOptions,
     ) -> Self {
-        let aspect_ratio = resolution_horizontal as f32 / resolution_vertical as f32;
-        let viewport_height = 2.0;
-        let viewport_width = viewport_height * aspect_ratio;
-        let focal_length: f32 = 1.0;
-
-        let horizontal = Vec3::from_values(viewport_width, 0.0, 0.0);
-        let vertical = Vec3::from_values(0.0, viewport_height, 0.0);
         Self {
             position: Point::from_values(position.x(), position.y(), position.z()),
             lookat,
-            up,
-            horizontal_fov,
-            resolution_horizontal,
-            resolution_vertical,
-            max_bounces,
-            horizontal,
-            vertical,
-            lower_left_corner: &(&(&position - &(&horizontal / &2.0)) - &(&vertical / &2.0))
-                - &Vec3::from_values(0.0, 0.0, focal_length),
+            horizontal_fov: options.horizontal_fov,
+            resolution_horizontal: options.resolution_horizontal,
+            resolution_vertical: options.resolution_vertical,
+            max_bounces: options.max_bounces,
+            projection: options.projection,
+            aperture_radius: options.aperture_radius,
+            focal_distance: options.focal_distance,
             transformation_matrix,
         }
     }
@@ -84,29 +95,164 @@ impl Camera {
         }
     }*/
 
-    pub fn construct_ray(&self, i: f64, j: f64) -> Ray {
-        //let u = (i / f64::from(self.resolution_horizontal as u32 - 1)) as f32;
-        //let v = (j / f64::from(self.resolution_vertical as u32 - 1)) as f32;
+    /// Ray through the pixel according to the projection, `None` for pixels outside of
+    /// the image circle of a fisheye
+    pub fn construct_ray(&self, i: f64, j: f64) -> Option<Ray> {
+        match self.projection {
+            Projection::Perspective => Some(Ray::from_values(
+                &self.position,
+                &self
+                    .transformation_matrix
+                    .transform_vec3(&self.camera_space_direction(i, j)),
+            )),
+            Projection::Orthographic { view_width } => {
+                Some(self.construct_orthographic_ray(i, j, view_width))
+            }
+            Projection::Equirectangular => Some(self.construct_equirectangular_ray(i, j)),
+            Projection::Fisheye => self.construct_fisheye_ray(i, j),
+        }
+    }
 
-        /* let computed_direction = &(&self.lower_left_corner
-        + &(&(&u * &self.horizontal) + &(&(&v * &self.vertical) - &self.position)));*/
+    /// Ray starting at a random point of the lens disk instead of the pinhole,
+    /// all rays through a pixel meet at the focal distance. Only perspective cameras have a lens.
+    pub fn construct_lens_ray<R: Rng>(&self, i: f64, j: f64, rng: &mut R) -> Option<Ray> {
+        if self.aperture_radius <= 0.0 || !matches!(self.projection, Projection::Perspective) {
+            return self.construct_ray(i, j);
+        }
 
-        let x_n = (i as f32 + 0.5) / self.resolution_horizontal as f32;
-        let y_n = (j as f32 + 0.5) / self.resolution_vertical as f32;
+        // The direction has a z of -1, so scaling it by the focal distance ends on the focal plane
+        let focus_point = &self.focal_distance * &self.camera_space_direction(i, j);
 
-        let fov_y = self.horizontal_fov * (self.resolution_vertical as f32 / self.resolution_horizontal as f32);
+        let radius = self.aperture_radius * rng.gen::<f32>().sqrt();
+        let angle = 2.0 * std::f32::consts::PI * rng.gen::<f32>();
+        let lens_point = Vec3::from_values(radius * angle.cos(), radius * angle.sin(), 0.0);
+
+        Some(Ray::from_values(
+            &(&self.position + &self.transformation_matrix.transform_vec3(&lens_point)),
+            &self
+                .transformation_matrix
+                .transform_vec3(&(&focus_point - &lens_point)),
+        ))
+    }
 
-        let x_i = (2.0 * x_n - 1.0) * self.horizontal_fov.to_radians().tan();
-        let y_i = (2.0 * y_n - 1.0) * fov_y.to_radians().tan();
+    /// Rays through the positions `spacing` pixels to the right of and above (i, j). Rays from
+    /// the lens start at the same lens point and pass through the neighbouring focus points.
+    pub fn construct_differentials(
+        &self,
+        ray: &Ray,
+        i: f64,
+        j: f64,
+        spacing: f64,
+    ) -> Option<RayDifferentials> {
+        let (x_ray, y_ray) =
+            if self.aperture_radius > 0.0 && matches!(self.projection, Projection::Perspective) {
+                let through_focus_point = |i: f64, j: f64| {
+                    let focus_point = &self.position
+                        + &self.transformation_matrix.transform_vec3(
+                            &(&self.focal_distance * &self.camera_space_direction(i, j)),
+                        );
+                    Ray::from_values(&ray.origin, &(&focus_point - &ray.origin))
+                };
+                (
+                    through_focus_point(i + spacing, j),
+                    through_focus_point(i, j + spacing),
+                )
+            } else {
+                (
+                    self.construct_ray(i + spacing, j)?,
+                    self.construct_ray(i, j + spacing)?,
+                )
+            };
 
+        Some(RayDifferentials {
+            x_origin: x_ray.origin,
+            x_direction: x_ray.direction,
+            y_origin: y_ray.origin,
+            y_direction: y_ray.direction,
+        })
+    }
+
+    fn construct_orthographic_ray(&self, i: f64, j: f64, view_width: f32) -> Ray {
+        let (x_s, y_s) = self.screen_position(i, j);
+        let view_height =
+            view_width * self.resolution_vertical as f32 / self.resolution_horizontal as f32;
+        let offset = Vec3::from_values(x_s * view_width / 2.0, y_s * view_height / 2.0, 0.0);
 
         Ray::from_values(
-            &self.position,
+            &(&self.position + &self.transformation_matrix.transform_vec3(&offset)),
             &self
                 .transformation_matrix
-                .transform_vec3(&Vec3::from_values(x_i, y_i, -1.0)),
+                .transform_vec3(&Vec3::from_values(0.0, 0.0, -1.0)),
+        )
+    }
+
+    fn construct_equirectangular_ray(&self, i: f64, j: f64) -> Ray {
+        let (x_s, y_s) = self.screen_position(i, j);
+        // The center of the image looks along -z
+        let longitude = x_s * std::f32::consts::PI;
+        let latitude = y_s * std::f32::consts::FRAC_PI_2;
+
+        let direction = Vec3::from_values(
+            longitude.sin() * latitude.cos(),
+            latitude.sin(),
+            -longitude.cos() * latitude.cos(),
+        );
+
+        Ray::from_values(
+            &self.position,
+            &self.transformation_matrix.transform_vec3(&direction),
         )
     }
+
+    fn construct_fisheye_ray(&self, i: f64, j: f64) -> Option<Ray> {
+        let (x_s, y_s) = self.screen_position(i, j);
+        // The image circle touches the left and right border
+        let y_s = y_s * self.resolution_vertical as f32 / self.resolution_horizontal as f32;
+        let radius = (x_s * x_s + y_s * y_s).sqrt();
+        if radius > 1.0 {
+            return None;
+        }
+
+        // Equidistant mapping, the angle to the view direction grows linearly with the radius
+        let theta = radius * self.horizontal_fov.to_radians();
+        let (x_d, y_d) = if radius > 0.0 {
+            (x_s / radius, y_s / radius)
+        } else {
+            (0.0, 0.0)
+        };
+        let direction = Vec3::from_values(theta.sin() * x_d, theta.sin() * y_d, -theta.cos());
+
+        Some(Ray::from_values(
+            &self.position,
+            &self.transformation_matrix.transform_vec3(&direction),
+        ))
+    }
+
+    // Position of the pixel center on the image, both coordinates in [-1, 1]
+    fn screen_position(&self, i: f64, j: f64) -> (f32, f32) {
+        let x_n = (i as f32 + 0.5) / self.resolution_horizontal as f32;
+        let y_n = (j as f32 + 0.5) / self.resolution_vertical as f32;
+
+        (2.0 * x_n - 1.0, 2.0 * y_n - 1.0)
+    }
+
+    // Direction through the pixel in camera space, the camera looks along -z
+    fn camera_space_direction(&self, i: f64, j: f64) -> Vec3 {
+        //let u = (i / f64::from(self.resolution_horizontal as u32 - 1)) as f32;
+        //let v = (j / f64::from(self.resolution_vertical as u32 - 1)) as f32;
+
+        /* let computed_direction = &(&self.lower_left_corner
+        + &(&(&u * &self.horizontal) + &(&(&v * &self.vertical) - &self.position)));*/
+
+        let (x_s, y_s) = self.screen_position(i, j);
+
+        let fov_y = self.horizontal_fov * (self.resolution_vertical as f32 / self.resolution_horizontal as f32);
+
+        let x_i = x_s * self.horizontal_fov.to_radians().tan();
+        let y_i = y_s * fov_y.to_radians().tan();
+
+        Vec3::from_values(x_i, y_i, -1.0)
+    }
 }
 
 impl<'de> Deserialize<'de> for Camera {
@@ -125,6 +271,41 @@ impl<'de> Deserialize<'de> for Camera {
             horizontal_fov: HorizontalFov,
             resolution: Resolution,
             max_bounces: MaxBounces,
+            projection: Option<ProjectionDeserialized>,
+            aperture: Option<Aperture>,
+            focal_distance: Option<FocalDistance>,
+        }
+
+        #[derive(Deserialize)]
+        enum ProjectionType {
+            #[serde(rename = "perspective")]
+            Perspective,
+            #[serde(rename = "orthographic")]
+            Orthographic,
+            #[serde(rename = "equirectangular")]
+            Equirectangular,
+            #[serde(rename = "fisheye")]
+            Fisheye,
+        }
+
+        #[derive(Deserialize)]
+        struct ProjectionDeserialized {
+            #[serde(rename = "@type")]
+            projection_type: ProjectionType,
+            #[serde(rename = "@view_width")]
+            view_width: Option<f32>,
+        }
+
+        #[derive(Deserialize)]
+        struct Aperture {
+            #[serde(rename = "@radius")]
+            radius: f32,
+        }
+
+        #[derive(Deserialize)]
+        struct FocalDistance {
+            #[serde(rename = "@distance")]
+            distance: f32,
         }
 
         #[derive(Deserialize)]
@@ -149,19 +330,45 @@ impl<'de> Deserialize<'de> for Camera {
 
         let deserialized_camera = DeserializeCamera::deserialize(deserializer)?;
 
+        let projection = match deserialized_camera.projection {
+            None => Projection::Perspective,
+            Some(projection) => match projection.projection_type {
+                ProjectionType::Perspective => Projection::Perspective,
+                ProjectionType::Orthographic => Projection::Orthographic {
+                    view_width: projection.view_width.ok_or_else(|| {
+                        D::Error::custom("Orthographic projection requires a view_width")
+                    })?,
+                },
+                ProjectionType::Equirectangular => Projection::Equirectangular,
+                ProjectionType::Fisheye => Projection::Fisheye,
+            },
+        };
+
+        // Without an explicit focal distance the lookat point is in focus
+        let focal_distance = match deserialized_camera.focal_distance {
+            Some(focal_distance) => focal_distance.distance,
+            None => (&deserialized_camera.lookat - &deserialized_camera.position).length(),
+        };
+
         Ok(Camera::from_values(
             deserialized_camera.position,
             deserialized_camera.lookat,
-            deserialized_camera.up,
-            deserialized_camera.horizontal_fov.angle,
-            deserialized_camera.resolution.resolution_horizontal,
-            deserialized_camera.resolution.resolution_vertical,
-            deserialized_camera.max_bounces.n,
             Mat4::construct_camera_transformation_matrix(
                 &deserialized_camera.up,
                 &deserialized_camera.lookat,
                 &deserialized_camera.position,
             ),
+            CameraOptions {
+                horizontal_fov: deserialized_camera.horizontal_fov.angle,
+                resolution_horizontal: deserialized_camera.resolution.resolution_horizontal,
+                resolution_vertical: deserialized_camera.resolution.resolution_vertical,
+                max_bounces: deserialized_camera.max_bounces.n,
+                projection,
+                aperture_radius: deserialized_camera
+                    .aperture
+                    .map_or(0.0, |aperture| aperture.radius),
+                focal_distance,
+            },
         ))
     }
 }
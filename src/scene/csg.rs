@@ -0,0 +1,277 @@
+use std::sync::Arc;
+
+use serde::Deserialize;
+
+use crate::tracer::{
+    aabb::Aabb,
+    hittable::{HitRecord, Hittable, Interval},
+    ray::Ray,
+};
+
+use super::surfaces::TransformationMatrices;
+
+#[derive(Deserialize, Clone, Copy)]
+pub enum CsgOperation {
+    #[serde(rename = "union")]
+    Union,
+    #[serde(rename = "intersection")]
+    Intersection,
+    #[serde(rename = "difference")]
+    Difference,
+}
+
+impl CsgOperation {
+    fn contains(&self, inside_first: bool, inside_second: bool) -> bool {
+        match self {
+            CsgOperation::Union => inside_first || inside_second,
+            CsgOperation::Intersection => inside_first && inside_second,
+            CsgOperation::Difference => inside_first && !inside_second,
+        }
+    }
+}
+
+/// Combines its children with a boolean operation. More than two children are combined
+/// from left to right, e.g. a difference subtracts all other children from the first one.
+/// The children are placed in the object space of the node.
+pub struct CsgNode {
+    operation: CsgOperation,
+    children: Vec<Arc<dyn Hittable>>,
+    transformation_matrices: TransformationMatrices,
+    bounding_box: Option<Aabb>,
+}
+
+// Boundary of an interval, sorted by t when combining two sets of intervals
+struct Boundary {
+    record: HitRecord,
+    is_enter: bool,
+    is_first: bool,
+}
+
+impl CsgNode {
+    pub fn new(
+        operation: CsgOperation,
+        children: Vec<Arc<dyn Hittable>>,
+        transformation_matrices: TransformationMatrices,
+    ) -> Self {
+        let bounding_box =
+            CsgNode::compute_bounding_box(operation, &children).map(|object_space_box| {
+                transformation_matrices.transform_bounding_box(&object_space_box)
+            });
+        Self {
+            operation,
+            children,
+            transformation_matrices,
+            bounding_box,
+        }
+    }
+
+    fn compute_bounding_box(
+        operation: CsgOperation,
+        children: &[Arc<dyn Hittable>],
+    ) -> Option<Aabb> {
+        match operation {
+            CsgOperation::Union => children.iter().try_fold(None, |accumulated, child| {
+                let child_box = child.bounding_box()?;
+                Some(Some(match accumulated {
+                    Some(accumulated) => Aabb::surrounding_box(&accumulated, &child_box),
+                    None => child_box,
+                }))
+            })?,
+            // Unbounded children (e.g. half-spaces) don't restrict the result
+            CsgOperation::Intersection => children
+                .iter()
+                .filter_map(|child| child.bounding_box())
+                .reduce(|accumulated, child_box| Aabb::overlap(&accumulated, &child_box)),
+            CsgOperation::Difference => children.first()?.bounding_box(),
+        }
+    }
+
+    // Sweeps over the boundaries of both sets and keeps those where the operation toggles
+    fn combine(&self, first: Vec<Interval>, second: Vec<Interval>) -> Vec<Interval> {
+        let mut boundaries = Vec::with_capacity(2 * (first.len() + second.len()));
+        for (intervals, is_first) in [(first, true), (second, false)] {
+            for interval in intervals {
+                boundaries.push(Boundary {
+                    record: interval.enter,
+                    is_enter: true,
+                    is_first,
+                });
+                boundaries.push(Boundary {
+                    record: interval.exit,
+                    is_enter: false,
+                    is_first,
+                });
+            }
+        }
+        boundaries.sort_by(|a, b| a.record.t.total_cmp(&b.record.t));
+
+        let mut intervals = vec![];
+        let mut inside_first = false;
+        let mut inside_second = false;
+        let mut enter: Option<HitRecord> = None;
+
+        for mut boundary in boundaries {
+            let was_inside = self.operation.contains(inside_first, inside_second);
+            if boundary.is_first {
+                inside_first = boundary.is_enter;
+            } else {
+                inside_second = boundary.is_enter;
+            }
+            let is_inside = self.operation.contains(inside_first, inside_second);
+
+            if was_inside == is_inside {
+                continue;
+            }
+
+            // Surfaces of subtracted objects face into the result
+            if !boundary.is_first && matches!(self.operation, CsgOperation::Difference) {
+                boundary.record.normal = -&boundary.record.normal;
+                boundary.record.front_face = !boundary.record.front_face;
+            }
+
+            if is_inside {
+                enter = Some(boundary.record);
+            } else if let Some(enter) = enter.take() {
+                intervals.push(Interval {
+                    enter,
+                    exit: boundary.record,
+                });
+            }
+        }
+
+        intervals
+    }
+}
+
+impl Hittable for CsgNode {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32, hit_record: &mut HitRecord) -> bool {
+        if let Some(bounding_box) = &self.bounding_box {
+            if !bounding_box.hit(ray, t_min, t_max) {
+                return false;
+            }
+        }
+
+        // Open ends of unbounded intervals have an infinite t and can't be hit
+        let closest = self
+            .intervals(ray)
+            .into_iter()
+            .flat_map(|interval| [interval.enter, interval.exit])
+            .find(|boundary| boundary.t.is_finite() && boundary.t > t_min && boundary.t < t_max);
+
+        match closest {
+            Some(boundary) => {
+                *hit_record = boundary;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        self.bounding_box
+    }
+
+    fn intervals(&self, ray: &Ray) -> Vec<Interval> {
+        let mut children = self.children.iter();
+        let Some(first_child) = children.next() else {
+            return vec![];
+        };
+
+        let transformed_ray = self
+            .transformation_matrices
+            .transform_ray_to_object_space(ray);
+
+        let mut intervals = children.fold(
+            first_child.intervals(&transformed_ray),
+            |accumulated, child| self.combine(accumulated, child.intervals(&transformed_ray)),
+        );
+
+        // The children reported points, normals and texture tangents in the object space of
+        // this node
+        for interval in &mut intervals {
+            for record in [&mut interval.enter, &mut interval.exit] {
+                record.point = ray.at(record.t);
+                record.normal = self
+                    .transformation_matrices
+                    .normal_matrix
+                    .transform_vec3(&record.normal)
+                    .unit_vector();
+                record.texture_tangents = record.texture_tangents.map(|texture_tangents| {
+                    self.transformation_matrices
+                        .transform_texture_tangents(&texture_tangents)
+                });
+            }
+        }
+
+        intervals
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::vec3::Vec3;
+
+    fn node(operation: CsgOperation) -> CsgNode {
+        CsgNode::new(operation, vec![], TransformationMatrices::default())
+    }
+
+    // Interval along the x axis, the normals point out of it
+    fn interval(enter: f32, exit: f32) -> Interval {
+        let mut interval = Interval {
+            enter: HitRecord::new(),
+            exit: HitRecord::new(),
+        };
+        interval.enter.t = enter;
+        interval.enter.normal = Vec3::from_values(-1.0, 0.0, 0.0);
+        interval.exit.t = exit;
+        interval.exit.normal = Vec3::from_values(1.0, 0.0, 0.0);
+        interval
+    }
+
+    fn spans(intervals: &[Interval]) -> Vec<(f32, f32)> {
+        intervals
+            .iter()
+            .map(|interval| (interval.enter.t, interval.exit.t))
+            .collect()
+    }
+
+    #[test]
+    fn union_merges_overlapping_intervals() {
+        let combined = node(CsgOperation::Union).combine(
+            vec![interval(1.0, 3.0), interval(6.0, 7.0)],
+            vec![interval(2.0, 5.0)],
+        );
+
+        assert_eq!(spans(&combined), vec![(1.0, 5.0), (6.0, 7.0)]);
+    }
+
+    #[test]
+    fn intersection_keeps_the_overlap() {
+        let combined = node(CsgOperation::Intersection)
+            .combine(vec![interval(1.0, 3.0)], vec![interval(2.0, 5.0)]);
+
+        assert_eq!(spans(&combined), vec![(2.0, 3.0)]);
+        assert!(node(CsgOperation::Intersection)
+            .combine(vec![interval(1.0, 2.0)], vec![interval(3.0, 4.0)])
+            .is_empty());
+    }
+
+    #[test]
+    fn difference_flips_the_normals_of_the_subtracted_object() {
+        let combined = node(CsgOperation::Difference)
+            .combine(vec![interval(1.0, 5.0)], vec![interval(2.0, 3.0)]);
+
+        assert_eq!(spans(&combined), vec![(1.0, 2.0), (3.0, 5.0)]);
+        assert_eq!(combined[0].exit.normal.x(), 1.0);
+        assert_eq!(combined[1].enter.normal.x(), -1.0);
+    }
+
+    #[test]
+    fn union_keeps_zero_thickness_intervals() {
+        let combined =
+            node(CsgOperation::Union).combine(vec![interval(1.0, 2.0)], vec![interval(4.0, 4.0)]);
+
+        assert_eq!(spans(&combined), vec![(1.0, 2.0), (4.0, 4.0)]);
+    }
+}
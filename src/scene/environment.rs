@@ -0,0 +1,179 @@
+use std::{f32::consts::PI, sync::Arc};
+
+use rand::{Rng, RngCore};
+use serde::{de::Error, Deserialize, Deserializer};
+
+use crate::utils::{
+    file_loader::{load_hdr_image, HdrImage},
+    vec3::{Color, Vec3},
+};
+
+/// Equirectangular image around the scene, seen by rays which miss all surfaces. The center
+/// of the image lies in the -z direction and the top row looks up along +y, like the image
+/// of the equirectangular camera.
+pub struct Environment {
+    image: HdrImage,
+    intensity: f32,
+    // Number of directions sampled per hit point when the environment lights the scene
+    light_samples: usize,
+    // Running sums of the sampling weights, over the rows and over the pixels of every row
+    row_cdf: Vec<f32>,
+    column_cdfs: Vec<f32>,
+}
+
+/// Direction towards the environment chosen proportional to its brightness
+pub struct EnvironmentSample {
+    pub direction: Vec3,
+    pub radiance: Color,
+    // Probability density per solid angle
+    pub pdf: f32,
+}
+
+impl Environment {
+    fn new(image: HdrImage, intensity: f32, light_samples: usize) -> Self {
+        let mut environment = Self {
+            image,
+            intensity,
+            light_samples,
+            row_cdf: vec![],
+            column_cdfs: vec![],
+        };
+        if light_samples > 0 {
+            environment.build_sampling_distribution();
+        }
+        environment
+    }
+
+    pub fn light_samples(&self) -> usize {
+        self.light_samples
+    }
+
+    pub fn radiance(&self, direction: &Vec3) -> Color {
+        let direction = direction.unit_vector();
+        let longitude = direction.x().atan2(-direction.z());
+        let latitude = direction.y().clamp(-1.0, 1.0).asin();
+
+        let u = (longitude / PI + 1.0) / 2.0;
+        let v = (1.0 - latitude / (PI / 2.0)) / 2.0;
+        let column = ((u * self.image.width as f32) as usize).min(self.image.width - 1);
+        let row = ((v * self.image.height as f32) as usize).min(self.image.height - 1);
+
+        self.pixel_radiance(row, column)
+    }
+
+    fn pixel_radiance(&self, row: usize, column: usize) -> Color {
+        &self.image.pixels[row * self.image.width + column] * &self.intensity
+    }
+
+    // Pixels are weighted by their luminance and the solid angle they cover, rows near the
+    // poles are squeezed together by the projection
+    fn build_sampling_distribution(&mut self) {
+        let (width, height) = (self.image.width, self.image.height);
+        self.column_cdfs = Vec::with_capacity(width * height);
+        self.row_cdf = Vec::with_capacity(height);
+
+        let mut total_weight = 0.0;
+        for row in 0..height {
+            let sin_theta = (PI * (row as f32 + 0.5) / height as f32).sin();
+            let mut row_weight = 0.0;
+            for column in 0..width {
+                let pixel = &self.image.pixels[row * width + column];
+                let luminance = 0.2126 * pixel.r() + 0.7152 * pixel.g() + 0.0722 * pixel.b();
+                row_weight += luminance.max(0.0) * sin_theta;
+                self.column_cdfs.push(row_weight);
+            }
+            total_weight += row_weight;
+            self.row_cdf.push(total_weight);
+        }
+    }
+
+    /// Random direction towards the environment, `None` if the environment is black
+    /// or doesn't light the scene
+    pub fn sample(&self, rng: &mut dyn RngCore) -> Option<EnvironmentSample> {
+        let total_weight = *self.row_cdf.last()?;
+        if total_weight <= 0.0 {
+            return None;
+        }
+        let (width, height) = (self.image.width, self.image.height);
+
+        let row = sample_cdf(&self.row_cdf, rng.gen::<f32>() * total_weight);
+        let row_cdf = &self.column_cdfs[row * width..(row + 1) * width];
+        let row_weight = row_cdf[width - 1];
+        let column = sample_cdf(row_cdf, rng.gen::<f32>() * row_weight);
+
+        let pixel_weight = row_cdf[column] - if column > 0 { row_cdf[column - 1] } else { 0.0 };
+        if pixel_weight <= 0.0 {
+            return None;
+        }
+
+        // Uniformly distributed within the pixel
+        let u = (column as f32 + rng.gen::<f32>()) / width as f32;
+        let v = (row as f32 + rng.gen::<f32>()) / height as f32;
+        let longitude = (2.0 * u - 1.0) * PI;
+        let latitude = (1.0 - 2.0 * v) * PI / 2.0;
+        let direction = Vec3::from_values(
+            longitude.sin() * latitude.cos(),
+            latitude.sin(),
+            -longitude.cos() * latitude.cos(),
+        );
+
+        // Every pixel covers 2pi/width * pi/height * sin(theta) of the sphere around its center
+        let sin_theta = (PI * (row as f32 + 0.5) / height as f32).sin();
+        let pixel_solid_angle = 2.0 * PI * PI * sin_theta / (width * height) as f32;
+        Some(EnvironmentSample {
+            direction,
+            radiance: self.pixel_radiance(row, column),
+            pdf: pixel_weight / total_weight / pixel_solid_angle,
+        })
+    }
+}
+
+// Index of the first entry of the running sum which exceeds the value
+fn sample_cdf(cdf: &[f32], value: f32) -> usize {
+    cdf.partition_point(|&sum| sum <= value).min(cdf.len() - 1)
+}
+
+fn default_intensity() -> f32 {
+    1.0
+}
+
+fn default_light_samples() -> usize {
+    16
+}
+
+pub fn deserialize_environment<'de, D>(
+    deserializer: D,
+) -> Result<Option<Arc<Environment>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    struct EnvironmentDeserialized {
+        #[serde(rename = "@name")]
+        name: String,
+        #[serde(rename = "@intensity")]
+        #[serde(default = "default_intensity")]
+        intensity: f32,
+        // Also light the scene, not only the background
+        #[serde(rename = "@lighting")]
+        #[serde(default)]
+        lighting: bool,
+        #[serde(rename = "@samples")]
+        #[serde(default = "default_light_samples")]
+        samples: usize,
+    }
+
+    let environment = EnvironmentDeserialized::deserialize(deserializer)?;
+    let image = load_hdr_image(&environment.name).map_err(D::Error::custom)?;
+    let light_samples = if environment.lighting {
+        environment.samples
+    } else {
+        0
+    };
+
+    Ok(Some(Arc::new(Environment::new(
+        image,
+        environment.intensity,
+        light_samples,
+    ))))
+}
@@ -1,19 +1,38 @@
-use std::ops::{AddAssign, MulAssign};
+use std::{
+    ops::{AddAssign, MulAssign},
+    sync::Arc,
+};
 
+use rand::{Rng, RngCore};
 use serde::Deserialize;
 
 use crate::{
-    tracer::{hittable::HitRecord, hittable_list::HittableList, ray::Ray},
+    tracer::{
+        hittable::{HitRecord, Hittable},
+        hittable_list::HittableList,
+        ray::Ray,
+    },
     utils::{
         deserialization_helpers::{deserialize_color, deserialize_point, deserialize_vector},
         vec3::{Color, Point, Vec3},
     },
 };
 
-use super::material::{Phong, Texture};
+use super::{
+    environment::Environment,
+    material::{Microfacet, Phong, ShadingModel, Texture, TextureFilter},
+    surfaces::tangent_space,
+};
 
+#[derive(Default)]
 pub struct LightList {
     pub light_list: Vec<Box<dyn Light>>,
+    // Surfaces with an emissive material and their index in the scene file, sampled as area lights
+    emissive_surfaces: Vec<(usize, Arc<dyn Hittable>)>,
+    // Environment map which lights the scene, sampled by its brightness
+    environment: Option<Arc<Environment>>,
+    // Settings of the first occluded ambient light, also used for occlusion renders
+    ambient_occlusion: Option<AmbientOcclusion>,
 }
 
 pub struct LightIntensity {
@@ -50,118 +69,606 @@ impl MulAssign<&Phong> for LightIntensity {
 
 impl MulAssign<&f32> for LightIntensity {
     fn mul_assign(&mut self, rhs: &f32) {
-        self.ambient *= &rhs;
-        self.diffuse *= &rhs;
-        self.specular *= &rhs;
+        self.ambient *= rhs;
+        self.diffuse *= rhs;
+        self.specular *= rhs;
     }
 }
 
-fn get_color_from_textures(texture_information: &Texture, texture_coordinate: &Vec3) -> Color {
-    let antialiasing_enabled = true;
-    if antialiasing_enabled {
-        let x: f32 = texture_coordinate.x();
-        let y = texture_coordinate.y();
+fn get_color_from_textures(
+    texture_information: &Texture,
+    texture_coordinate: &Vec3,
+    texture_derivatives: Option<&(Vec3, Vec3)>,
+) -> Color {
+    // Coordinates and derivatives of the surface are moved into the image first
+    let texture_coordinate = &texture_information.transform_coordinate(texture_coordinate);
+    let texture_derivatives = texture_derivatives.map(|(x_derivative, y_derivative)| {
+        (
+            texture_information.transform_derivative(x_derivative),
+            texture_information.transform_derivative(y_derivative),
+        )
+    });
 
-        let x_floor = f32::floor(x * texture_information.width as f32) as isize;
-        let y_floor = f32::floor(y * texture_information.height as f32) as isize;
+    match (texture_information.filter, texture_derivatives) {
+        (TextureFilter::Nearest, _) => nearest_lookup(texture_information, texture_coordinate),
+        (TextureFilter::Trilinear, Some((x_derivative, y_derivative))) => {
+            // The longer side of the area covered by the pixel decides the level
+            let footprint = texel_length(texture_information, &x_derivative)
+                .max(texel_length(texture_information, &y_derivative));
+            trilinear_lookup(texture_information, texture_coordinate, footprint)
+        }
+        (TextureFilter::Anisotropic, Some((x_derivative, y_derivative))) => anisotropic_lookup(
+            texture_information,
+            texture_coordinate,
+            &x_derivative,
+            &y_derivative,
+        ),
+        // Without derivatives (e.g. for reflected rays) the full resolution is used
+        _ => bilinear_lookup(texture_information, texture_coordinate, 0),
+    }
+}
 
-        let x_frac = x * texture_information.width as f32 - x_floor as f32;
-        let y_frac = y * texture_information.height as f32 - y_floor as f32;
+fn nearest_lookup(texture_information: &Texture, texture_coordinate: &Vec3) -> Color {
+    let x_converted = f32::floor(texture_coordinate.x() * texture_information.width) as isize;
+    let y_converted = f32::floor(texture_coordinate.y() * texture_information.height) as isize;
 
-        let x1 = (x_floor as usize).rem_euclid(texture_information.width as usize);
-        let y1 = (y_floor as usize).rem_euclid(texture_information.height as usize);
+    texture_information.texel(0, x_converted, y_converted)
+}
 
-        let x2 = ((x_floor + 1) as usize).rem_euclid(texture_information.width as usize);
-        let y2 = ((y_floor + 1) as usize).rem_euclid(texture_information.height as usize);
+fn bilinear_lookup(
+    texture_information: &Texture,
+    texture_coordinate: &Vec3,
+    level: usize,
+) -> Color {
+    let (width, height, _) = texture_information.level(level);
+    let x: f32 = texture_coordinate.x();
+    let y = texture_coordinate.y();
 
-        let color00 =
-            texture_information.texture_pixels[texture_information.width as usize * y1 + x1];
-        let color10 =
-            texture_information.texture_pixels[texture_information.width as usize * y1 + x2];
-        let color01 =
-            texture_information.texture_pixels[texture_information.width as usize * y2 + x1];
-        let color11 =
-            texture_information.texture_pixels[texture_information.width as usize * y2 + x2];
+    let x_floor = f32::floor(x * width as f32) as isize;
+    let y_floor = f32::floor(y * height as f32) as isize;
 
-        let color_top = &(&color00 * &(1.0 - x_frac)) + &(&color10 * &x_frac);
-        let color_bottom = &(&color01 * &(1.0 - x_frac)) + &(&color11 * &x_frac);
+    let x_frac = x * width as f32 - x_floor as f32;
+    let y_frac = y * height as f32 - y_floor as f32;
 
-        &(&color_top * &(1.0 - y_frac)) + &(&color_bottom * &y_frac)
-    } else {
-        // Former implementation without anti-aliasing and interpolation
-        let x_converted = (f32::floor(texture_coordinate.x() * texture_information.width) as isize)
-            .rem_euclid(texture_information.width as isize) as usize;
-        let y_converted = (f32::floor(texture_coordinate.y() * texture_information.height) as isize)
-            .rem_euclid(texture_information.height as isize) as usize;
+    // Neighbours outside of the image are resolved by the wrap mode of the texture
+    let color00 = texture_information.texel(level, x_floor, y_floor);
+    let color10 = texture_information.texel(level, x_floor + 1, y_floor);
+    let color01 = texture_information.texel(level, x_floor, y_floor + 1);
+    let color11 = texture_information.texel(level, x_floor + 1, y_floor + 1);
 
-        texture_information.texture_pixels
-            [(texture_information.width as usize * y_converted) + x_converted]
+    let color_top = &(&color00 * &(1.0 - x_frac)) + &(&color10 * &x_frac);
+    let color_bottom = &(&color01 * &(1.0 - x_frac)) + &(&color11 * &x_frac);
+
+    &(&color_top * &(1.0 - y_frac)) + &(&color_bottom * &y_frac)
+}
+
+// Length of a step in texture coordinates, measured in pixels of the full resolution
+fn texel_length(texture_information: &Texture, derivative: &Vec3) -> f32 {
+    (derivative.x() * texture_information.width).hypot(derivative.y() * texture_information.height)
+}
+
+// Blends the two levels whose pixel size is closest to the footprint
+fn trilinear_lookup(
+    texture_information: &Texture,
+    texture_coordinate: &Vec3,
+    footprint: f32,
+) -> Color {
+    let level = footprint
+        .max(1.0)
+        .log2()
+        .min((texture_information.level_count() - 1) as f32);
+    let finer_level = level.floor() as usize;
+    let fraction = level - finer_level as f32;
+
+    let finer_color = bilinear_lookup(texture_information, texture_coordinate, finer_level);
+    if fraction <= 0.0 {
+        return finer_color;
     }
+    let coarser_color = bilinear_lookup(texture_information, texture_coordinate, finer_level + 1);
+
+    &(&finer_color * &(1.0 - fraction)) + &(&coarser_color * &fraction)
 }
 
-pub trait Light {
-    fn calculate_light_intensities(&self, ray: &Ray, hit_record: &HitRecord) -> LightIntensity;
-    fn check_if_in_shadow(&self, hit_record: &HitRecord, surfaces: &HittableList) -> bool;
+const MAX_ANISOTROPY: f32 = 8.0;
+
+// Footprints stretched in one direction (e.g. floors seen at grazing angles) are covered by
+// several lookups along the longer axis, each at the resolution of the shorter axis
+fn anisotropic_lookup(
+    texture_information: &Texture,
+    texture_coordinate: &Vec3,
+    x_derivative: &Vec3,
+    y_derivative: &Vec3,
+) -> Color {
+    let x_length = texel_length(texture_information, x_derivative);
+    let y_length = texel_length(texture_information, y_derivative);
+    let (major_axis, major_length, minor_length) = if x_length >= y_length {
+        (x_derivative, x_length, y_length)
+    } else {
+        (y_derivative, y_length, x_length)
+    };
+
+    // Beyond the maximum anisotropy the footprint is blurred along the shorter axis as well
+    let minor_length = minor_length.max(major_length / MAX_ANISOTROPY);
+    let sample_count = if minor_length > 0.0 {
+        (major_length / minor_length)
+            .ceil()
+            .clamp(1.0, MAX_ANISOTROPY) as usize
+    } else {
+        1
+    };
+
+    let mut color = Color::new();
+    for sample in 0..sample_count {
+        let offset = (sample as f32 + 0.5) / sample_count as f32 - 0.5;
+        let sample_coordinate = texture_coordinate + &(&offset * major_axis);
+        color += &trilinear_lookup(texture_information, &sample_coordinate, minor_length);
+    }
+    &color / &(sample_count as f32)
 }
 
-#[derive(Deserialize)]
-pub struct AmbientLight {
-    #[serde(deserialize_with = "deserialize_color")]
+/// Light arriving at a surface point from a single direction
+pub struct LightSample {
+    // Unit vector from the surface point towards the light
+    pub direction: Vec3,
     pub color: Color,
 }
 
-impl Light for AmbientLight {
-    fn calculate_light_intensities(&self, _ray: &Ray, hit_record: &HitRecord) -> LightIntensity {
-        let mut light_intesity = LightIntensity::new();
-
-        let color = if hit_record.material.get_texture_information().is_some() {
-            get_color_from_textures(
-                &hit_record.material.get_texture_information().unwrap(),
-                &hit_record.texture_coordinate.unwrap(),
-            )
-        } else {
-            hit_record.material.get_color()
-        };
+pub enum Illumination {
+    // Reaches the surface from all directions
+    Ambient(Color),
+    Direct(LightSample),
+}
 
-        light_intesity.ambient = &color * &self.color;
-        light_intesity
+pub trait Light: Send + Sync {
+    /// Light arriving at the hit point, `None` if the light doesn't reach it
+    fn illuminate(&self, hit_record: &HitRecord) -> Option<Illumination>;
+    /// Fraction of the light which isn't blocked by other surfaces, between 0 and 1
+    fn visibility(
+        &self,
+        hit_record: &HitRecord,
+        surfaces: &HittableList,
+        rng: &mut dyn RngCore,
+    ) -> f32;
+}
+
+// Single shadow ray, either the light is fully visible or not at all
+fn shadow_ray_visibility(
+    hit_record: &HitRecord,
+    surfaces: &HittableList,
+    direction: Vec3,
+    distance: f32,
+) -> f32 {
+    let in_shadow = surfaces.shadow_check(
+        &Ray::from_values(&hit_record.point, &direction),
+        0.00001, // offset prevent intersection with object itself
+        distance,
+    );
+    if in_shadow {
+        0.0
+    } else {
+        1.0
+    }
+}
+
+pub fn get_surface_color(hit_record: &HitRecord) -> Color {
+    if hit_record.material.get_texture_information().is_some() {
+        get_color_from_textures(
+            hit_record.material.get_texture_information().unwrap(),
+            &hit_record.texture_coordinate.unwrap(),
+            hit_record.texture_derivatives.as_ref(),
+        )
+    } else {
+        hit_record.material.get_color()
     }
+}
+
+/// Fraction of the light which is reflected diffusely, used for indirect bounces
+pub fn diffuse_albedo(hit_record: &HitRecord) -> Color {
+    let surface_color = get_surface_color(hit_record);
+    match hit_record.material.get_shading_model() {
+        ShadingModel::Phong(phong) => &surface_color * &phong.kd,
+        ShadingModel::Microfacet(microfacet) => {
+            &surface_color * &(1.0 - microfacet.metallic.clamp(0.0, 1.0))
+        }
+    }
+}
+
+fn phong_intensity(
+    ray: &Ray,
+    hit_record: &HitRecord,
+    surface_color: &Color,
+    phong: &Phong,
+    light_sample: &LightSample,
+) -> LightIntensity {
+    let mut light_intensity = LightIntensity::new();
+
+    // Calculate Diffuse
+    let light_vector = &light_sample.direction;
+    let intensity = hit_record.normal.dot(light_vector).max(0.0);
+    let diffuse_intensity = &(surface_color * &light_sample.color) * &intensity;
+
+    // Calculate Specular
+    //r = 2(n ⋅ l)n – l
+    let reflection_vector = (&(&(2.0 * hit_record.normal.dot(light_vector)) * &hit_record.normal)
+        - light_vector)
+        .unit_vector();
 
-    fn check_if_in_shadow(&self, _hit_record: &HitRecord, _sufaces: &HittableList) -> bool {
-        // Ambient light is not affected by shadows
-        false
+    let eye_vector = -&ray.direction.unit_vector();
+    let specular_intensity = &eye_vector
+        .dot(&reflection_vector)
+        .max(0.0)
+        .powf(phong.exponent)
+        * &light_sample.color;
+    light_intensity.diffuse = diffuse_intensity;
+    light_intensity.specular = specular_intensity;
+    light_intensity
+}
+
+// Lower bound of the roughness, a perfectly smooth surface would only reflect point lights
+// in a single direction
+const MIN_ROUGHNESS: f32 = 0.03;
+
+/// Cook-Torrance with the GGX distribution, Smith's shadowing term and Schlick's Fresnel
+/// approximation. The light color is the light reflected by a white diffuse surface lit head-on,
+/// like for Phong, so the BRDF is scaled by pi.
+fn microfacet_intensity(
+    ray: &Ray,
+    hit_record: &HitRecord,
+    base_color: &Color,
+    microfacet: &Microfacet,
+    light_sample: &LightSample,
+) -> LightIntensity {
+    let mut light_intensity = LightIntensity::new();
+
+    let normal = hit_record.normal.unit_vector();
+    let light_vector = &light_sample.direction;
+    let eye_vector = -&ray.direction.unit_vector();
+
+    let normal_dot_light = normal.dot(light_vector);
+    let normal_dot_eye = normal.dot(&eye_vector);
+    if normal_dot_light <= 0.0 || normal_dot_eye <= 0.0 {
+        return light_intensity;
     }
+
+    let halfway_vector = (light_vector + &eye_vector).unit_vector();
+    let normal_dot_halfway = normal.dot(&halfway_vector).max(0.0);
+    let eye_dot_halfway = eye_vector.dot(&halfway_vector).max(0.0);
+
+    let alpha = microfacet.roughness.clamp(MIN_ROUGHNESS, 1.0).powi(2);
+    let alpha_squared = alpha * alpha;
+
+    let distribution = alpha_squared
+        / (std::f32::consts::PI
+            * (normal_dot_halfway.powi(2) * (alpha_squared - 1.0) + 1.0).powi(2));
+
+    let smith_g1 = |cosine: f32| {
+        2.0 * cosine / (cosine + (alpha_squared + (1.0 - alpha_squared) * cosine.powi(2)).sqrt())
+    };
+    let geometry = smith_g1(normal_dot_light) * smith_g1(normal_dot_eye);
+
+    // Dielectrics reflect about 4% at normal incidence, metals reflect their base color
+    let metallic = microfacet.metallic.clamp(0.0, 1.0);
+    let dielectric_reflectance = Color::from_values(0.04, 0.04, 0.04);
+    let reflectance_at_normal =
+        &(&dielectric_reflectance * &(1.0 - metallic)) + &(base_color * &metallic);
+    let fresnel_weight = (1.0 - eye_dot_halfway).powi(5);
+    let fresnel = &(&reflectance_at_normal * &(1.0 - fresnel_weight))
+        + &Color::from_values(fresnel_weight, fresnel_weight, fresnel_weight);
+
+    let specular_factor = std::f32::consts::PI * distribution * geometry
+        / (4.0 * normal_dot_light * normal_dot_eye)
+        * normal_dot_light;
+    let diffuse_weight =
+        &Color::from_values(1.0 - fresnel.r(), 1.0 - fresnel.g(), 1.0 - fresnel.b())
+            * &(1.0 - metallic);
+
+    light_intensity.diffuse =
+        &(&(&diffuse_weight * base_color) * &light_sample.color) * &normal_dot_light;
+    light_intensity.specular = &(&fresnel * &light_sample.color) * &specular_factor;
+    light_intensity
+}
+
+fn default_occlusion_samples() -> usize {
+    16
+}
+
+fn default_occlusion_distance() -> f32 {
+    f32::INFINITY
 }
 
-impl Default for LightList {
+/// Hemisphere visibility around a hit point, darkens creases and contact areas
+#[derive(Deserialize, Clone, Copy)]
+pub struct AmbientOcclusion {
+    #[serde(rename = "@samples")]
+    #[serde(default = "default_occlusion_samples")]
+    pub samples: usize,
+    // Surfaces further away don't occlude, needed for closed scenes
+    #[serde(rename = "@distance")]
+    #[serde(default = "default_occlusion_distance")]
+    pub distance: f32,
+}
+
+impl Default for AmbientOcclusion {
     fn default() -> Self {
         Self {
-            light_list: Default::default(),
+            samples: default_occlusion_samples(),
+            distance: default_occlusion_distance(),
+        }
+    }
+}
+
+impl AmbientOcclusion {
+    /// Fraction of cosine weighted directions on the front side which aren't blocked
+    pub fn visibility(
+        &self,
+        hit_record: &HitRecord,
+        surfaces: &HittableList,
+        rng: &mut dyn RngCore,
+    ) -> f32 {
+        let bias = 0.001; // Small bias value to mitigate surface acne
+        let normal = if hit_record.front_face {
+            hit_record.normal.unit_vector()
+        } else {
+            -&hit_record.normal.unit_vector()
+        };
+        let origin = &hit_record.point + &(&bias * &normal);
+
+        let sample_count = self.samples.max(1);
+        let unoccluded_samples = (0..sample_count)
+            .filter(|_| {
+                !surfaces.shadow_check(
+                    &Ray::from_values(&origin, &cosine_weighted_direction(&normal, rng)),
+                    0.00001,
+                    self.distance,
+                )
+            })
+            .count();
+        unoccluded_samples as f32 / sample_count as f32
+    }
+}
+
+#[derive(Deserialize)]
+pub struct AmbientLight {
+    #[serde(deserialize_with = "deserialize_color")]
+    pub color: Color,
+    #[serde(default)]
+    pub occlusion: Option<AmbientOcclusion>,
+}
+
+impl Light for AmbientLight {
+    fn illuminate(&self, _hit_record: &HitRecord) -> Option<Illumination> {
+        Some(Illumination::Ambient(self.color))
+    }
+
+    fn visibility(
+        &self,
+        hit_record: &HitRecord,
+        surfaces: &HittableList,
+        rng: &mut dyn RngCore,
+    ) -> f32 {
+        // Ambient light is not affected by shadows, only by the occlusion of nearby surfaces
+        match &self.occlusion {
+            Some(occlusion) => occlusion.visibility(hit_record, surfaces, rng),
+            None => 1.0,
         }
     }
 }
 
 impl LightList {
+    /// Registers all emissive surfaces of the scene as area lights
+    pub fn add_emissive_surfaces(&mut self, surfaces: &HittableList) {
+        self.emissive_surfaces.extend(
+            surfaces
+                .objects
+                .iter()
+                .cloned()
+                .enumerate()
+                .filter(|(_, surface)| surface.get_emission().is_some()),
+        );
+    }
+
+    /// Whether the emission of the hit surface is already sampled as an area light
+    pub fn samples_emission_of(&self, hit_record: &HitRecord) -> bool {
+        self.emissive_surfaces
+            .iter()
+            .any(|(object_index, _)| hit_record.object_index == Some(*object_index))
+    }
+
+    /// Lets the environment light the scene if it was configured for lighting
+    pub fn add_environment_light(&mut self, environment: Arc<Environment>) {
+        if environment.light_samples() > 0 {
+            self.environment = Some(environment);
+        }
+    }
+
+    /// Occlusion settings of the scene, the defaults if no ambient light is occluded
+    pub fn ambient_occlusion(&self) -> AmbientOcclusion {
+        self.ambient_occlusion.unwrap_or_default()
+    }
+
+    /// Whether escaping rays see an environment which is already sampled as a light
+    pub fn samples_environment(&self) -> bool {
+        self.environment.is_some()
+    }
+
     pub fn calculate_final_color(
         &self,
         ray: &Ray,
         hit_record: &HitRecord,
         surfaces: &HittableList,
+        rng: &mut dyn RngCore,
+    ) -> Color {
+        let light_intensity = self.light_intensity(ray, hit_record, surfaces, true, rng);
+
+        let emission = hit_record
+            .material
+            .get_emission()
+            .map_or_else(Color::new, |emission| emission.color);
+        &(&(&light_intensity.ambient + &light_intensity.diffuse) + &light_intensity.specular)
+            + &emission
+    }
+
+    /// Light reflected directly from the light sources without ambient light and emission
+    pub fn calculate_direct_light(
+        &self,
+        ray: &Ray,
+        hit_record: &HitRecord,
+        surfaces: &HittableList,
+        rng: &mut dyn RngCore,
     ) -> Color {
+        let light_intensity = self.light_intensity(ray, hit_record, surfaces, false, rng);
+        &light_intensity.diffuse + &light_intensity.specular
+    }
+
+    fn light_intensity(
+        &self,
+        ray: &Ray,
+        hit_record: &HitRecord,
+        surfaces: &HittableList,
+        include_ambient: bool,
+        rng: &mut dyn RngCore,
+    ) -> LightIntensity {
         let mut light_intensity = LightIntensity::new();
+        let surface_color = get_surface_color(hit_record);
+        let shading_model = hit_record.material.get_shading_model();
+
+        let shade = |hit_record: &HitRecord, light_sample: &LightSample| match &shading_model {
+            ShadingModel::Phong(phong) => {
+                phong_intensity(ray, hit_record, &surface_color, phong, light_sample)
+            }
+            ShadingModel::Microfacet(microfacet) => {
+                microfacet_intensity(ray, hit_record, &surface_color, microfacet, light_sample)
+            }
+        };
 
+        // Shadow rays are only traced for lights which reach the hit point
         for light_source in &self.light_list {
-            if light_source.check_if_in_shadow(&hit_record, &surfaces) {
-                continue;
+            match light_source.illuminate(hit_record) {
+                Some(Illumination::Ambient(color)) if include_ambient => {
+                    let visibility = light_source.visibility(hit_record, surfaces, rng);
+                    light_intensity.ambient += &(&surface_color * &(&color * &visibility));
+                }
+                Some(Illumination::Direct(mut light_sample)) => {
+                    let visibility = light_source.visibility(hit_record, surfaces, rng);
+                    if visibility <= 0.0 {
+                        continue;
+                    }
+                    light_sample.color *= &visibility;
+                    light_intensity += &shade(hit_record, &light_sample);
+                }
+                _ => {}
+            }
+        }
+
+        for (_, emissive_surface) in &self.emissive_surfaces {
+            let sample_count = emissive_surface
+                .get_emission()
+                .map_or(0, |emission| emission.samples.max(1));
+            for _ in 0..sample_count {
+                if let Some(light_sample) =
+                    sample_emissive_surface(emissive_surface.as_ref(), hit_record, surfaces, rng)
+                {
+                    light_intensity += &shade(hit_record, &light_sample);
+                }
+            }
+        }
+
+        // Like the diffuse bounces of the path integrator, the environment lights the side of
+        // the surface which faces the viewer
+        if let Some(environment) = &self.environment {
+            let facing_hit_record = facing_hit_record(ray, hit_record);
+            for _ in 0..environment.light_samples() {
+                if let Some(light_sample) =
+                    sample_environment(environment, &facing_hit_record, surfaces, rng)
+                {
+                    light_intensity += &shade(&facing_hit_record, &light_sample);
+                }
             }
+        }
 
-            light_intensity += &light_source.calculate_light_intensities(&ray, &hit_record);
+        // The microfacet model is energy conserving by itself and needs no weights
+        if let ShadingModel::Phong(phong) = &shading_model {
+            light_intensity *= phong;
         }
 
-        light_intensity *= &hit_record.material.get_phong();
+        light_intensity
+    }
+}
 
-        &(&light_intensity.ambient + &light_intensity.diffuse) + &light_intensity.specular
+/// Light arriving from one random point of an emissive surface, weighted so that the
+/// average over all samples estimates the light of the whole surface. Emissive surfaces
+/// shine on both sides. `None` if the point is occluded.
+fn sample_emissive_surface(
+    emissive_surface: &dyn Hittable,
+    hit_record: &HitRecord,
+    surfaces: &HittableList,
+    rng: &mut dyn RngCore,
+) -> Option<LightSample> {
+    let emission = emissive_surface.get_emission()?;
+    let surface_sample = emissive_surface.sample_surface(rng)?;
+
+    let light_vector = &surface_sample.point - &hit_record.point;
+    let distance = light_vector.length();
+    if distance <= 0.0 {
+        return None;
     }
+    let direction = &light_vector / &distance;
+
+    let cosine_at_light = surface_sample.normal.dot(&direction).abs();
+    // The sample point itself must not occlude the light
+    if cosine_at_light <= 0.0
+        || surfaces.shadow_check(
+            &Ray::from_values(&hit_record.point, &direction),
+            0.00001,
+            distance * 0.999,
+        )
+    {
+        return None;
+    }
+
+    // Radiance over the pdf of the sampled direction, divided by pi so that a surface
+    // covering the whole hemisphere lights like a light of the emission color
+    let weight = cosine_at_light * surface_sample.area
+        / (std::f32::consts::PI * distance * distance * emission.samples.max(1) as f32);
+    Some(LightSample {
+        direction,
+        color: &emission.color * &weight,
+    })
+}
+
+// Copy of the hit record whose normal points to the side the ray came from
+fn facing_hit_record(ray: &Ray, hit_record: &HitRecord) -> HitRecord {
+    let mut facing_hit_record = hit_record.clone();
+    if ray.direction.dot(&hit_record.normal) > 0.0 {
+        facing_hit_record.normal = -&hit_record.normal;
+    }
+    facing_hit_record
+}
+
+/// Light arriving from one direction of the environment, weighted like the samples of emissive
+/// surfaces. `None` if the direction is below the surface or blocked, the normal of the hit
+/// record has to face the incoming ray.
+fn sample_environment(
+    environment: &Environment,
+    hit_record: &HitRecord,
+    surfaces: &HittableList,
+    rng: &mut dyn RngCore,
+) -> Option<LightSample> {
+    let environment_sample = environment.sample(rng)?;
+    let direction = environment_sample.direction;
+
+    if hit_record.normal.dot(&direction) <= 0.0
+        || shadow_ray_visibility(hit_record, surfaces, direction, f32::INFINITY) <= 0.0
+    {
+        return None;
+    }
+
+    let weight =
+        1.0 / (std::f32::consts::PI * environment_sample.pdf * environment.light_samples() as f32);
+    Some(LightSample {
+        direction,
+        color: &environment_sample.radiance * &weight,
+    })
 }
 
 #[derive(Deserialize)]
@@ -173,52 +680,22 @@ struct ParallelLight {
 }
 
 impl Light for ParallelLight {
-    fn calculate_light_intensities(&self, ray: &Ray, hit_record: &HitRecord) -> LightIntensity {
-        let mut light_intensity = LightIntensity::new();
-
-        let color = if hit_record.material.get_texture_information().is_some() {
-            get_color_from_textures(
-                &hit_record.material.get_texture_information().unwrap(),
-                &hit_record.texture_coordinate.unwrap(),
-            )
-        } else {
-            hit_record.material.get_color()
-        };
-
-        // Calculate Diffuse
-        let light_vector = (-&self.direction).unit_vector();
-        let intensity = hit_record.normal.dot(&light_vector).max(0.0);
-        let diffuse_intensity = &(&color * &self.color) * &intensity;
-
-        // Calculate Specular
-        //r = 2(n ⋅ l)n – l
-        let reflection_vector = (&(&(&2.0 * &(hit_record.normal.dot(&light_vector)))
-            * &hit_record.normal)
-            - &light_vector)
-            .unit_vector();
-
-        let eye_vector = -&ray.direction.unit_vector();
-        let specular_intensity = &(eye_vector
-            .dot(&reflection_vector)
-            .max(0.0)
-            .powf(hit_record.material.get_phong().exponent))
-            * &self.color;
-        light_intensity.diffuse = diffuse_intensity;
-        light_intensity.specular = specular_intensity;
-        light_intensity
+    fn illuminate(&self, _hit_record: &HitRecord) -> Option<Illumination> {
+        Some(Illumination::Direct(LightSample {
+            direction: (-&self.direction).unit_vector(),
+            color: self.color,
+        }))
     }
 
-    fn check_if_in_shadow(&self, hit_record: &HitRecord, surfaces: &HittableList) -> bool {
+    fn visibility(
+        &self,
+        hit_record: &HitRecord,
+        surfaces: &HittableList,
+        _rng: &mut dyn RngCore,
+    ) -> f32 {
         //Check if light_vector intersects with any object
         let light_vector = (-&self.direction).unit_vector();
-        surfaces.shadow_check(
-            &Ray {
-                origin: hit_record.point,
-                direction: light_vector,
-            },
-            0.00001, // offset prevent intersection with object itself
-            f32::INFINITY,
-        )
+        shadow_ray_visibility(hit_record, surfaces, light_vector, f32::INFINITY)
     }
 }
 
@@ -231,53 +708,23 @@ struct PointLight {
 }
 
 impl Light for PointLight {
-    fn calculate_light_intensities(&self, ray: &Ray, hit_record: &HitRecord) -> LightIntensity {
-        let mut light_intensity = LightIntensity::new();
-
-        let color = if hit_record.material.get_texture_information().is_some() {
-            get_color_from_textures(
-                &hit_record.material.get_texture_information().unwrap(),
-                &hit_record.texture_coordinate.unwrap(),
-            )
-        } else {
-            hit_record.material.get_color()
-        };
-
-        // Calculate Diffuse
-        let light_vector = (&self.position - &hit_record.point).unit_vector();
-        let intensity = hit_record.normal.dot(&light_vector).max(0.0);
-        let diffuse_intensity = &(&color * &self.color) * &intensity;
-
-        // Calculate Specular
-        //r = 2(n ⋅ l)n – l
-        let reflection_vector = (&(&(&2.0 * &(hit_record.normal.dot(&light_vector)))
-            * &hit_record.normal)
-            - &light_vector)
-            .unit_vector();
-
-        let eye_vector = -&ray.direction.unit_vector();
-        let specular_intensity = &(eye_vector
-            .dot(&reflection_vector)
-            .max(0.0)
-            .powf(hit_record.material.get_phong().exponent))
-            * &self.color;
-        light_intensity.diffuse = diffuse_intensity;
-        light_intensity.specular = specular_intensity;
-        light_intensity
+    fn illuminate(&self, hit_record: &HitRecord) -> Option<Illumination> {
+        Some(Illumination::Direct(LightSample {
+            direction: (&self.position - &hit_record.point).unit_vector(),
+            color: self.color,
+        }))
     }
 
-    fn check_if_in_shadow(&self, hit_record: &HitRecord, surfaces: &HittableList) -> bool {
+    fn visibility(
+        &self,
+        hit_record: &HitRecord,
+        surfaces: &HittableList,
+        _rng: &mut dyn RngCore,
+    ) -> f32 {
         let mut light_vector = &self.position - &hit_record.point;
         let light_vector_length = light_vector.length();
         light_vector = light_vector.unit_vector();
-        surfaces.shadow_check(
-            &Ray {
-                origin: hit_record.point,
-                direction: light_vector,
-            },
-            0.00001, // offset prevent intersection with object itself
-            light_vector_length,
-        )
+        shadow_ray_visibility(hit_record, surfaces, light_vector, light_vector_length)
     }
 }
 
@@ -294,90 +741,143 @@ struct SpotLight {
 }
 
 impl Light for SpotLight {
-    fn calculate_light_intensities(&self, ray: &Ray, hit_record: &HitRecord) -> LightIntensity {
-        let mut light_intensity = LightIntensity::new();
-
-        let color = if hit_record.material.get_texture_information().is_some() {
-            get_color_from_textures(
-                &hit_record.material.get_texture_information().unwrap(),
-                &hit_record.texture_coordinate.unwrap(),
-            )
-        } else {
-            hit_record.material.get_color()
-        };
-
+    fn illuminate(&self, hit_record: &HitRecord) -> Option<Illumination> {
         let incident_light_direction = (-&(&self.position - &hit_record.point)).unit_vector();
         let incident_angle = incident_light_direction
             .dot(&self.direction.unit_vector())
             .acos();
 
-        if incident_angle > self.fall_off.alpha2.to_radians() {
-            return light_intensity;
-        } else if incident_angle >= 0.0 && incident_angle <= self.fall_off.alpha1.to_radians() {
+        let color = if incident_angle > self.fall_off.alpha2.to_radians() {
+            return None;
+        } else if (0.0..=self.fall_off.alpha1.to_radians()).contains(&incident_angle) {
             // with angle between zero and angle1, the light should be just like a point light
-
-            // Calculate Diffuse
-            let light_vector = (&self.position - &hit_record.point).unit_vector();
-            let intensity = hit_record.normal.dot(&light_vector).max(0.0);
-            let diffuse_intensity = &(&color * &self.color) * &intensity;
-
-            // Calculate Specular
-            //r = 2(n ⋅ l)n – l
-            let reflection_vector = (&(&(&2.0 * &(hit_record.normal.dot(&light_vector)))
-                * &hit_record.normal)
-                - &light_vector)
-                .unit_vector();
-
-            let eye_vector = -&ray.direction.unit_vector();
-            let specular_intensity = &(eye_vector
-                .dot(&reflection_vector)
-                .max(0.0)
-                .powf(hit_record.material.get_phong().exponent))
-                * &self.color;
-            light_intensity.diffuse = diffuse_intensity;
-            light_intensity.specular = specular_intensity;
-            return light_intensity;
+            self.color
         } else {
-            let light_vector = (&self.position - &hit_record.point).unit_vector();
-            let intensity = hit_record.normal.dot(&light_vector).max(0.0);
-            let diffuse_intensity = &(&color * &self.color) * &intensity;
-
-            // Calculate Specular
-            //r = 2(n ⋅ l)n – l
-            let reflection_vector = (&(&(&2.0 * &(hit_record.normal.dot(&light_vector)))
-                * &hit_record.normal)
-                - &light_vector)
-                .unit_vector();
-
-            let eye_vector = -&ray.direction.unit_vector();
-            let specular_intensity = &(eye_vector
-                .dot(&reflection_vector)
-                .max(0.0)
-                .powf(hit_record.material.get_phong().exponent))
-                * &self.color;
-            light_intensity.diffuse = diffuse_intensity;
-            light_intensity.specular = specular_intensity;
-
             let interpolation_factor = 1.0
                 - ((incident_angle - self.fall_off.alpha1.to_radians())
                     / (self.fall_off.alpha2.to_radians() - self.fall_off.alpha1.to_radians()))
                 .clamp(0.0, 1.0);
 
-            light_intensity *= &interpolation_factor;
-            light_intensity
-        }
+            &self.color * &interpolation_factor
+        };
+
+        Some(Illumination::Direct(LightSample {
+            direction: (&self.position - &hit_record.point).unit_vector(),
+            color,
+        }))
     }
 
-    fn check_if_in_shadow(&self, hit_record: &HitRecord, surfaces: &HittableList) -> bool {
+    fn visibility(
+        &self,
+        hit_record: &HitRecord,
+        surfaces: &HittableList,
+        _rng: &mut dyn RngCore,
+    ) -> f32 {
         let light_vector = (&self.position - &hit_record.point).unit_vector();
-        surfaces.shadow_check(
-            &Ray {
-                origin: hit_record.point,
-                direction: light_vector,
-            },
-            0.00001, // offset prevent intersection with object itself
-            f32::INFINITY,
-        )
+        shadow_ray_visibility(hit_record, surfaces, light_vector, f32::INFINITY)
+    }
+}
+
+enum AreaLightShape {
+    // Parallelogram around the position, spanned by the two edges
+    Rectangle { edge_u: Vec3, edge_v: Vec3 },
+    Disk { normal: Vec3, radius: f32 },
+    Sphere { radius: f32 },
+}
+
+/// Light with an extent, shaded like a point light at its center. Its visibility is
+/// estimated with shadow rays towards random points of the light, which gives soft shadows.
+/// Rectangles shine towards `edge_u x edge_v` and disks towards their normal.
+struct AreaLight {
+    color: Color,
+    position: Point,
+    shape: AreaLightShape,
+    shadow_samples: usize,
+}
+
+impl AreaLight {
+    // Flat lights only shine on the side they face, weakened towards grazing angles
+    fn emission_cosine(&self, hit_record: &HitRecord) -> f32 {
+        let normal = match &self.shape {
+            AreaLightShape::Rectangle { edge_u, edge_v } => edge_u.cross(edge_v),
+            AreaLightShape::Disk { normal, .. } => *normal,
+            AreaLightShape::Sphere { .. } => return 1.0,
+        };
+        normal
+            .unit_vector()
+            .dot(&(&hit_record.point - &self.position).unit_vector())
+            .max(0.0)
+    }
+
+    fn sample_point(&self, hit_record: &HitRecord, rng: &mut dyn RngCore) -> Point {
+        match &self.shape {
+            AreaLightShape::Rectangle { edge_u, edge_v } => {
+                let u = rng.gen::<f32>() - 0.5;
+                let v = rng.gen::<f32>() - 0.5;
+                &self.position + &(&(&u * edge_u) + &(&v * edge_v))
+            }
+            AreaLightShape::Disk { normal, radius } => {
+                sample_disk(&self.position, normal, *radius, rng)
+            }
+            // Seen from the hit point a sphere covers the same area as a disk facing it
+            AreaLightShape::Sphere { radius } => {
+                let normal = &hit_record.point - &self.position;
+                sample_disk(&self.position, &normal, *radius, rng)
+            }
+        }
+    }
+}
+
+/// Uniformly distributed point on a disk
+pub fn sample_disk(center: &Point, normal: &Vec3, radius: f32, rng: &mut dyn RngCore) -> Point {
+    let (tangent, bitangent) = tangent_space(&normal.unit_vector());
+
+    let distance = radius * rng.gen::<f32>().sqrt();
+    let angle = 2.0 * std::f32::consts::PI * rng.gen::<f32>();
+    center + &(&(&(distance * angle.cos()) * &tangent) + &(&(distance * angle.sin()) * &bitangent))
+}
+
+/// Random direction in the hemisphere around the normal, distributed by the cosine to the normal
+pub fn cosine_weighted_direction(normal: &Vec3, rng: &mut dyn RngCore) -> Vec3 {
+    // Points uniformly distributed on the unit disk, projected up onto the hemisphere
+    let disk_point = sample_disk(&Point::new(), normal, 1.0, rng);
+    let height = (1.0 - disk_point.dot(&disk_point)).max(0.0).sqrt();
+    &disk_point + &(&height * &normal.unit_vector())
+}
+
+impl Light for AreaLight {
+    fn illuminate(&self, hit_record: &HitRecord) -> Option<Illumination> {
+        let emission_cosine = self.emission_cosine(hit_record);
+        if emission_cosine <= 0.0 {
+            return None;
+        }
+
+        Some(Illumination::Direct(LightSample {
+            direction: (&self.position - &hit_record.point).unit_vector(),
+            color: &self.color * &emission_cosine,
+        }))
+    }
+
+    fn visibility(
+        &self,
+        hit_record: &HitRecord,
+        surfaces: &HittableList,
+        rng: &mut dyn RngCore,
+    ) -> f32 {
+        let sample_count = self.shadow_samples.max(1);
+        let visible_samples: f32 = (0..sample_count)
+            .map(|_| {
+                let light_vector = &self.sample_point(hit_record, rng) - &hit_record.point;
+                let light_vector_length = light_vector.length();
+                shadow_ray_visibility(
+                    hit_record,
+                    surfaces,
+                    light_vector.unit_vector(),
+                    light_vector_length,
+                )
+            })
+            .sum();
+        visible_samples / sample_count as f32
     }
 }
 
@@ -389,12 +889,18 @@ struct FallOff {
     alpha2: f32,
 }
 
+fn default_shadow_samples() -> usize {
+    16
+}
+
 #[derive(Deserialize)]
 enum LightEnum {
     #[serde(rename = "ambient_light")]
     Ambient {
         #[serde(deserialize_with = "deserialize_color")]
         color: Color,
+        #[serde(default)]
+        occlusion: Option<AmbientOcclusion>,
     },
     #[serde(rename = "parallel_light")]
     Parallel {
@@ -421,6 +927,46 @@ enum LightEnum {
         #[serde(rename = "falloff")]
         fall_off: FallOff,
     },
+    #[serde(rename = "rect_light")]
+    Rectangle {
+        #[serde(deserialize_with = "deserialize_color")]
+        color: Color,
+        #[serde(deserialize_with = "deserialize_point")]
+        position: Point,
+        #[serde(deserialize_with = "deserialize_vector")]
+        edge_u: Vec3,
+        #[serde(deserialize_with = "deserialize_vector")]
+        edge_v: Vec3,
+        #[serde(rename = "@samples")]
+        #[serde(default = "default_shadow_samples")]
+        shadow_samples: usize,
+    },
+    #[serde(rename = "disk_light")]
+    Disk {
+        #[serde(deserialize_with = "deserialize_color")]
+        color: Color,
+        #[serde(deserialize_with = "deserialize_point")]
+        position: Point,
+        #[serde(deserialize_with = "deserialize_vector")]
+        normal: Vec3,
+        #[serde(rename = "@radius")]
+        radius: f32,
+        #[serde(rename = "@samples")]
+        #[serde(default = "default_shadow_samples")]
+        shadow_samples: usize,
+    },
+    #[serde(rename = "sphere_light")]
+    Sphere {
+        #[serde(deserialize_with = "deserialize_color")]
+        color: Color,
+        #[serde(deserialize_with = "deserialize_point")]
+        position: Point,
+        #[serde(rename = "@radius")]
+        radius: f32,
+        #[serde(rename = "@samples")]
+        #[serde(default = "default_shadow_samples")]
+        shadow_samples: usize,
+    },
 }
 
 impl<'de> Deserialize<'de> for LightList {
@@ -431,17 +977,29 @@ impl<'de> Deserialize<'de> for LightList {
         #[derive(Deserialize)]
         struct InnerLights {
             #[serde(rename = "$value")]
+            #[serde(default)]
             lights: Vec<LightEnum>,
         }
 
         let inner_lights: InnerLights = InnerLights::deserialize(deserializer)?;
 
+        let ambient_occlusion =
+            inner_lights
+                .lights
+                .iter()
+                .find_map(|light_type| match light_type {
+                    LightEnum::Ambient { occlusion, .. } => *occlusion,
+                    _ => None,
+                });
+
         let light_list: Vec<Box<dyn Light>> = inner_lights
             .lights
             .into_iter()
             .map(|light_type| -> Box<dyn Light> {
                 match light_type {
-                    LightEnum::Ambient { color } => Box::new(AmbientLight { color }),
+                    LightEnum::Ambient { color, occlusion } => {
+                        Box::new(AmbientLight { color, occlusion })
+                    }
                     LightEnum::Parallel { color, direction } => {
                         Box::new(ParallelLight { color, direction })
                     }
@@ -459,10 +1017,50 @@ impl<'de> Deserialize<'de> for LightList {
                         direction,
                         fall_off,
                     }),
+                    LightEnum::Rectangle {
+                        color,
+                        position,
+                        edge_u,
+                        edge_v,
+                        shadow_samples,
+                    } => Box::new(AreaLight {
+                        color,
+                        position,
+                        shape: AreaLightShape::Rectangle { edge_u, edge_v },
+                        shadow_samples,
+                    }),
+                    LightEnum::Disk {
+                        color,
+                        position,
+                        normal,
+                        radius,
+                        shadow_samples,
+                    } => Box::new(AreaLight {
+                        color,
+                        position,
+                        shape: AreaLightShape::Disk { normal, radius },
+                        shadow_samples,
+                    }),
+                    LightEnum::Sphere {
+                        color,
+                        position,
+                        radius,
+                        shadow_samples,
+                    } => Box::new(AreaLight {
+                        color,
+                        position,
+                        shape: AreaLightShape::Sphere { radius },
+                        shadow_samples,
+                    }),
                 }
             })
             .collect();
 
-        Ok(LightList { light_list })
+        Ok(LightList {
+            light_list,
+            emissive_surfaces: vec![],
+            environment: None,
+            ambient_occlusion,
+        })
     }
 }
@@ -1,16 +1,24 @@
-use std::rc::Rc;
+use std::sync::Arc;
 
-use serde::{Deserialize, Deserializer};
+use serde::{de::Error, Deserialize, Deserializer};
 
-use crate::utils::{deserialization_helpers::deserialize_color, vec3::Color, file_loader::{load_texture_file}};
+use crate::utils::{
+    deserialization_helpers::{deserialize_color, deserialize_optional_color},
+    file_loader::load_texture_file,
+    vec3::{Color, Vec3},
+};
 
-pub trait Material {
+pub trait Material: Send + Sync {
     fn get_color(&self) -> Color;
-    fn get_phong(&self) -> Phong;
+    fn get_shading_model(&self) -> ShadingModel;
     fn get_reflectance(&self) -> Reflectance;
     fn get_transmittance(&self) -> Transmittance;
     fn get_refraction(&self) -> Refraction;
     fn get_texture_information(&self) -> Option<&Texture>;
+    fn get_absorption(&self) -> Option<Color>;
+    fn get_emission(&self) -> Option<Emission>;
+    /// Cut out parts of the surface are skipped by all rays as if there was no surface
+    fn is_cut_out(&self, texture_coordinate: &Vec3) -> bool;
 }
 
 #[derive(Deserialize)]
@@ -21,6 +29,13 @@ pub struct MaterialSolid {
     pub reflectance: Reflectance,
     pub transmittance: Transmittance,
     pub refraction: Refraction,
+    // Absorption coefficients per unit of distance travelled inside of the object
+    #[serde(default)]
+    #[serde(deserialize_with = "deserialize_optional_color")]
+    pub absorption: Option<Color>,
+    // Light emitted by the surface itself, emissive surfaces also act as area lights
+    #[serde(default)]
+    pub emission: Option<Emission>,
 }
 
 impl MaterialSolid {
@@ -35,18 +50,23 @@ impl MaterialSolid {
             },
             reflectance: Reflectance { r: 20.0 },
             transmittance: Transmittance { t: 15.0 },
-            refraction: Refraction { iof: 1.0 },
+            refraction: Refraction {
+                iof: 1.0,
+                fresnel: None,
+            },
+            absorption: None,
+            emission: None,
         }
     }
 }
 
 impl Material for MaterialSolid {
     fn get_color(&self) -> Color {
-        self.color.clone()
+        self.color
     }
 
-    fn get_phong(&self) -> Phong {
-        self.phong.clone()
+    fn get_shading_model(&self) -> ShadingModel {
+        ShadingModel::Phong(self.phong.clone())
     }
 
     fn get_reflectance(&self) -> Reflectance {
@@ -64,6 +84,18 @@ impl Material for MaterialSolid {
     fn get_texture_information(&self) -> Option<&Texture> {
         None
     }
+
+    fn get_absorption(&self) -> Option<Color> {
+        self.absorption
+    }
+
+    fn get_emission(&self) -> Option<Emission> {
+        self.emission
+    }
+
+    fn is_cut_out(&self, _texture_coordinate: &Vec3) -> bool {
+        false
+    }
 }
 
 #[derive(Deserialize)]
@@ -73,6 +105,17 @@ pub struct MaterialTextured {
     pub reflectance: Reflectance,
     pub transmittance: Transmittance,
     pub refraction: Refraction,
+    // Absorption coefficients per unit of distance travelled inside of the object
+    #[serde(default)]
+    #[serde(deserialize_with = "deserialize_optional_color")]
+    pub absorption: Option<Color>,
+    // Light emitted by the surface itself, emissive surfaces also act as area lights
+    #[serde(default)]
+    pub emission: Option<Emission>,
+    // Spheres and meshes have holes where the texture alpha lies below this value
+    #[serde(rename = "@alpha_cutoff")]
+    #[serde(default)]
+    pub alpha_cutoff: Option<f32>,
 }
 
 impl Material for MaterialTextured {
@@ -80,8 +123,8 @@ impl Material for MaterialTextured {
         Color::new()
     }
 
-    fn get_phong(&self) -> Phong {
-        self.phong.clone()
+    fn get_shading_model(&self) -> ShadingModel {
+        ShadingModel::Phong(self.phong.clone())
     }
 
     fn get_reflectance(&self) -> Reflectance {
@@ -99,6 +142,85 @@ impl Material for MaterialTextured {
     fn get_texture_information(&self) -> Option<&Texture> {
         Some(&self.texture)
     }
+
+    fn get_absorption(&self) -> Option<Color> {
+        self.absorption
+    }
+
+    fn get_emission(&self) -> Option<Emission> {
+        self.emission
+    }
+
+    fn is_cut_out(&self, texture_coordinate: &Vec3) -> bool {
+        self.alpha_cutoff
+            .is_some_and(|alpha_cutoff| self.texture.alpha(texture_coordinate) < alpha_cutoff)
+    }
+}
+
+/// Physically based material, the base color is taken from the texture if one is given
+#[derive(Deserialize)]
+pub struct MaterialPbr {
+    #[serde(default)]
+    #[serde(deserialize_with = "deserialize_optional_color")]
+    pub color: Option<Color>,
+    pub texture: Option<Texture>,
+    pub microfacet: Microfacet,
+    #[serde(default)]
+    pub reflectance: Reflectance,
+    #[serde(default)]
+    pub transmittance: Transmittance,
+    #[serde(default)]
+    pub refraction: Refraction,
+    #[serde(default)]
+    #[serde(deserialize_with = "deserialize_optional_color")]
+    pub absorption: Option<Color>,
+    // Light emitted by the surface itself, emissive surfaces also act as area lights
+    #[serde(default)]
+    pub emission: Option<Emission>,
+}
+
+impl Material for MaterialPbr {
+    fn get_color(&self) -> Color {
+        self.color.unwrap_or(Color::new())
+    }
+
+    fn get_shading_model(&self) -> ShadingModel {
+        ShadingModel::Microfacet(self.microfacet.clone())
+    }
+
+    fn get_reflectance(&self) -> Reflectance {
+        self.reflectance.clone()
+    }
+
+    fn get_transmittance(&self) -> Transmittance {
+        self.transmittance.clone()
+    }
+
+    fn get_refraction(&self) -> Refraction {
+        self.refraction.clone()
+    }
+
+    fn get_texture_information(&self) -> Option<&Texture> {
+        self.texture.as_ref()
+    }
+
+    fn get_absorption(&self) -> Option<Color> {
+        self.absorption
+    }
+
+    fn get_emission(&self) -> Option<Emission> {
+        self.emission
+    }
+
+    fn is_cut_out(&self, _texture_coordinate: &Vec3) -> bool {
+        false
+    }
+}
+
+/// How the light of the light sources is reflected by a surface
+pub enum ShadingModel {
+    Phong(Phong),
+    Microfacet(Microfacet),
 }
 
 #[derive(Deserialize, Clone)]
@@ -113,13 +235,61 @@ pub struct Phong {
     pub exponent: f32,
 }
 
+/// Cook-Torrance BRDF with the GGX distribution, see "Microfacet Models for Refraction
+/// through Rough Surfaces" (Walter et al.)
 #[derive(Deserialize, Clone)]
+pub struct Microfacet {
+    #[serde(rename = "@roughness")]
+    pub roughness: f32,
+    #[serde(rename = "@metallic")]
+    pub metallic: f32,
+}
+
+/// Light emitted by a surface, sampled like an area light by the surfaces which are lit by it
+#[derive(Clone, Copy)]
+pub struct Emission {
+    pub color: Color,
+    // Shadow rays per hit point, more samples give smoother soft shadows
+    pub samples: usize,
+}
+
+fn default_emission_samples() -> usize {
+    8
+}
+
+impl<'de> Deserialize<'de> for Emission {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        struct EmissionDeserialized {
+            #[serde(rename = "@r")]
+            r: f32,
+            #[serde(rename = "@g")]
+            g: f32,
+            #[serde(rename = "@b")]
+            b: f32,
+            #[serde(rename = "@samples")]
+            #[serde(default = "default_emission_samples")]
+            samples: usize,
+        }
+
+        let emission = EmissionDeserialized::deserialize(deserializer)?;
+        Ok(Emission {
+            color: Color::from_values(emission.r, emission.g, emission.b),
+            samples: emission.samples,
+        })
+    }
+}
+
+#[derive(Deserialize, Clone, Default)]
 pub struct Reflectance {
     #[serde(rename = "@r")]
     pub r: f32,
 }
 
-#[derive(Deserialize, Clone)]
+#[derive(Deserialize, Clone, Default)]
 pub struct Transmittance {
     #[serde(rename = "@t")]
     pub t: f32,
@@ -129,18 +299,249 @@ pub struct Transmittance {
 pub struct Refraction {
     #[serde(rename = "@iof")]
     pub iof: f32,
+    // Opt-in, derives the split between reflection and refraction from the angle of incidence
+    #[serde(rename = "@fresnel")]
+    #[serde(default)]
+    pub fresnel: Option<Fresnel>,
+}
+
+// Materials without a refraction element (PBR) see no change of medium when transmitting
+impl Default for Refraction {
+    fn default() -> Self {
+        Self {
+            iof: 1.0,
+            fresnel: None,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+pub enum Fresnel {
+    #[serde(rename = "exact")]
+    Exact,
+    #[serde(rename = "schlick")]
+    Schlick,
+}
+
+// Encoding of the texture file, data maps like normal maps are stored as linear values
+#[derive(Deserialize, Clone, Copy, Default)]
+pub enum ColorSpace {
+    #[default]
+    #[serde(rename = "srgb")]
+    Srgb,
+    #[serde(rename = "linear")]
+    Linear,
+}
+
+// Filtering of texture lookups, the mipmapped filters choose the resolution by the area a
+// pixel covers on the surface and fall back to bilinear filtering for secondary rays
+#[derive(Deserialize, Clone, Copy, Default)]
+pub enum TextureFilter {
+    #[serde(rename = "nearest")]
+    Nearest,
+    #[default]
+    #[serde(rename = "bilinear")]
+    Bilinear,
+    #[serde(rename = "trilinear")]
+    Trilinear,
+    // Several trilinear lookups along the longer axis of the area covered by the pixel
+    #[serde(rename = "anisotropic")]
+    Anisotropic,
+}
+
+impl TextureFilter {
+    pub fn uses_mipmaps(&self) -> bool {
+        matches!(self, TextureFilter::Trilinear | TextureFilter::Anisotropic)
+    }
+}
+
+// Continuation of the texture outside of the unit square
+#[derive(Deserialize, Clone, Copy, Default)]
+pub enum WrapMode {
+    #[default]
+    #[serde(rename = "repeat")]
+    Repeat,
+    // The pixels at the border are stretched
+    #[serde(rename = "clamp")]
+    Clamp,
+    // Every other repetition is flipped, so neighbouring tiles fit together at the seams
+    #[serde(rename = "mirror")]
+    Mirror,
+    // Transparent black
+    #[serde(rename = "border")]
+    Border,
+}
+
+impl WrapMode {
+    /// Pixel index within a row or column of the given size, `None` for the border
+    pub fn wrap(&self, index: isize, size: usize) -> Option<usize> {
+        let size = size as isize;
+        match self {
+            WrapMode::Repeat => Some(index.rem_euclid(size) as usize),
+            WrapMode::Clamp => Some(index.clamp(0, size - 1) as usize),
+            WrapMode::Mirror => {
+                let index = index.rem_euclid(2 * size);
+                Some(if index < size {
+                    index
+                } else {
+                    2 * size - 1 - index
+                } as usize)
+            }
+            WrapMode::Border => (0..size).contains(&index).then_some(index as usize),
+        }
+    }
+}
+
+fn default_uv_scale() -> f32 {
+    1.0
+}
+
+/// Texture downscaled by a power of two
+#[derive(Clone)]
+pub struct MipLevel {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<Color>,
 }
 
 #[derive(Deserialize, Clone)]
 pub struct Texture {
     #[serde(rename = "@name")]
     pub name: String,
+    #[serde(rename = "@colorspace")]
+    #[serde(default)]
+    pub colorspace: ColorSpace,
+    #[serde(rename = "@filter")]
+    #[serde(default)]
+    pub filter: TextureFilter,
+    #[serde(rename = "@wrap")]
+    #[serde(default)]
+    pub wrap: WrapMode,
+    // The texture coordinates of the surface are scaled, rotated counterclockwise by the
+    // rotation in degrees and then offset before the lookup
+    #[serde(rename = "@scale_u")]
+    #[serde(default = "default_uv_scale")]
+    pub scale_u: f32,
+    #[serde(rename = "@scale_v")]
+    #[serde(default = "default_uv_scale")]
+    pub scale_v: f32,
+    #[serde(rename = "@rotation")]
+    #[serde(default)]
+    pub rotation: f32,
+    #[serde(rename = "@offset_u")]
+    #[serde(default)]
+    pub offset_u: f32,
+    #[serde(rename = "@offset_v")]
+    #[serde(default)]
+    pub offset_v: f32,
     #[serde(skip_deserializing)]
     pub width: f32,
     #[serde(skip_deserializing)]
     pub height: f32,
     #[serde(skip_deserializing)]
     pub texture_pixels: Vec<Color>,
+    // Opacity of every pixel, one for textures without alpha channel
+    #[serde(skip_deserializing)]
+    pub texture_alpha: Vec<f32>,
+    // Level one (half the resolution) and coarser, level zero are the texture pixels
+    #[serde(skip_deserializing)]
+    pub mip_levels: Vec<MipLevel>,
+}
+
+impl Texture {
+    /// Opacity of the texel at the texture coordinate of the surface
+    pub fn alpha(&self, texture_coordinate: &Vec3) -> f32 {
+        let texture_coordinate = self.transform_coordinate(texture_coordinate);
+        let x = self.wrap.wrap(
+            f32::floor(texture_coordinate.x() * self.width) as isize,
+            self.width as usize,
+        );
+        let y = self.wrap.wrap(
+            f32::floor(texture_coordinate.y() * self.height) as isize,
+            self.height as usize,
+        );
+
+        match (x, y) {
+            (Some(x), Some(y)) => self.texture_alpha[self.width as usize * y + x],
+            _ => 0.0,
+        }
+    }
+
+    /// Maps a texture coordinate of the surface into the image by the UV transform
+    pub fn transform_coordinate(&self, texture_coordinate: &Vec3) -> Vec3 {
+        let transformed = self.transform_derivative(texture_coordinate);
+        Vec3::from_values(
+            transformed.x() + self.offset_u,
+            transformed.y() + self.offset_v,
+            texture_coordinate.z(),
+        )
+    }
+
+    /// Maps a change of the texture coordinate, the offset doesn't apply to it
+    pub fn transform_derivative(&self, derivative: &Vec3) -> Vec3 {
+        let (sin, cos) = self.rotation.to_radians().sin_cos();
+        let u = derivative.x() * self.scale_u;
+        let v = derivative.y() * self.scale_v;
+        Vec3::from_values(cos * u - sin * v, sin * u + cos * v, derivative.z())
+    }
+
+    /// Pixel of a mipmap level, indices outside of the level are resolved by the wrap mode
+    pub fn texel(&self, level: usize, x: isize, y: isize) -> Color {
+        let (width, height, pixels) = self.level(level);
+        match (self.wrap.wrap(x, width), self.wrap.wrap(y, height)) {
+            (Some(x), Some(y)) => pixels[width * y + x],
+            _ => Color::new(),
+        }
+    }
+
+    /// Halves the resolution down to a single pixel, every pixel averages 2x2 pixels of the
+    /// finer level. The last row or column of odd sizes is only covered by clamping.
+    pub fn build_mipmaps(&mut self) {
+        self.mip_levels.clear();
+        let (mut width, mut height) = (self.width as usize, self.height as usize);
+
+        while width > 1 || height > 1 {
+            let (_, _, pixels) = self.level(self.mip_levels.len());
+            let (next_width, next_height) = ((width / 2).max(1), (height / 2).max(1));
+
+            let mut next_pixels = Vec::with_capacity(next_width * next_height);
+            for y in 0..next_height {
+                let (y1, y2) = (2 * y, (2 * y + 1).min(height - 1));
+                for x in 0..next_width {
+                    let (x1, x2) = (2 * x, (2 * x + 1).min(width - 1));
+                    let sum = &(&pixels[width * y1 + x1] + &pixels[width * y1 + x2])
+                        + &(&pixels[width * y2 + x1] + &pixels[width * y2 + x2]);
+                    next_pixels.push(&sum / &4.0);
+                }
+            }
+
+            self.mip_levels.push(MipLevel {
+                width: next_width,
+                height: next_height,
+                pixels: next_pixels,
+            });
+            (width, height) = (next_width, next_height);
+        }
+    }
+
+    pub fn level_count(&self) -> usize {
+        1 + self.mip_levels.len()
+    }
+
+    /// Width, height and pixels of a mipmap level, zero is the full resolution
+    pub fn level(&self, level: usize) -> (usize, usize, &[Color]) {
+        match level.checked_sub(1) {
+            None => (
+                self.width as usize,
+                self.height as usize,
+                &self.texture_pixels,
+            ),
+            Some(index) => {
+                let mip_level = &self.mip_levels[index];
+                (mip_level.width, mip_level.height, &mip_level.pixels)
+            }
+        }
+    }
 }
 
 #[derive(Deserialize)]
@@ -149,18 +550,32 @@ pub enum MaterialEnum {
     Solid(MaterialSolid),
     #[serde(rename = "material_textured")]
     Textured(MaterialTextured),
+    #[serde(rename = "material_pbr")]
+    Pbr(MaterialPbr),
 }
 
-pub fn deserialize_material<'de, D>(deserializer: D) -> Result<Rc<dyn Material>, D::Error>
+pub fn deserialize_material<'de, D>(deserializer: D) -> Result<Arc<dyn Material>, D::Error>
 where
     D: Deserializer<'de>,
 {
     let material: MaterialEnum = Deserialize::deserialize(deserializer)?;
     match material {
-        MaterialEnum::Solid(material_solid) => Ok(Rc::new(material_solid) as Rc<dyn Material>),
+        MaterialEnum::Solid(material_solid) => Ok(Arc::new(material_solid) as Arc<dyn Material>),
         MaterialEnum::Textured(mut material_textured) => {
-            material_textured.texture = load_texture_file(&material_textured.texture.name);
-            Ok(Rc::new(material_textured) as Rc<dyn Material>)
+            material_textured.texture =
+                load_texture_file(&material_textured.texture).map_err(D::Error::custom)?;
+            Ok(Arc::new(material_textured) as Arc<dyn Material>)
+        }
+        MaterialEnum::Pbr(mut material_pbr) => {
+            if material_pbr.color.is_none() && material_pbr.texture.is_none() {
+                return Err(D::Error::custom(
+                    "material_pbr requires either a color or a texture",
+                ));
+            }
+            if let Some(texture) = &material_pbr.texture {
+                material_pbr.texture = Some(load_texture_file(texture).map_err(D::Error::custom)?);
+            }
+            Ok(Arc::new(material_pbr) as Arc<dyn Material>)
         }
     }
 }
@@ -1,5 +1,8 @@
 pub mod camera;
+pub mod csg;
+pub mod environment;
 pub mod light;
 pub mod material;
+#[allow(clippy::module_inception)]
 pub mod scene;
 pub mod surfaces;
@@ -1,12 +1,19 @@
+use std::sync::Arc;
+
 use deserialization_helpers::deserialize_color;
 use serde::Deserialize;
 
 use crate::{
-    tracer::hittable_list::HittableList,
-    utils::{deserialization_helpers, vec3::Color},
+    tracer::{hittable_list::HittableList, ray::Ray},
+    utils::{deserialization_helpers, tone_mapping::ToneMapping, vec3::Color},
 };
 
-use super::{camera::Camera, light::LightList, surfaces::deserialize_surfaces};
+use super::{
+    camera::Camera,
+    environment::{deserialize_environment, Environment},
+    light::LightList,
+    surfaces::deserialize_surfaces,
+};
 
 #[derive(Deserialize)]
 pub struct Scene {
@@ -14,9 +21,25 @@ pub struct Scene {
     pub output_file: String,
     #[serde(deserialize_with = "deserialize_color")]
     pub background_color: Color,
+    // Replaces the background color if present
+    #[serde(default)]
+    #[serde(deserialize_with = "deserialize_environment")]
+    pub environment: Option<Arc<Environment>>,
+    #[serde(default)]
+    pub tone_mapping: ToneMapping,
     pub camera: Camera,
     #[serde(default)]
     pub lights: LightList,
     #[serde(deserialize_with = "deserialize_surfaces")]
     pub surfaces: HittableList,
 }
+
+impl Scene {
+    /// Color of rays which don't hit any surface
+    pub fn background(&self, ray: &Ray) -> Color {
+        match &self.environment {
+            Some(environment) => environment.radiance(&ray.direction),
+            None => self.background_color,
+        }
+    }
+}
@@ -1,15 +1,18 @@
-use std::rc::Rc;
+use std::sync::Arc;
 
-use serde::{Deserialize, Deserializer};
+use rand::{Rng, RngCore};
+use serde::{de::Error, Deserialize, Deserializer};
 
 use crate::{
     tracer::{
-        hittable::{HitRecord, Hittable},
+        aabb::Aabb,
+        hittable::{HitRecord, Hittable, Interval, SurfaceSample},
         hittable_list::HittableList,
         ray::Ray,
+        triangle_bvh::TriangleBvh,
     },
     utils::{
-        deserialization_helpers::{deserialize_point, deserialize_vector},
+        deserialization_helpers::{deserialize_matrix, deserialize_point, deserialize_vector},
         file_loader,
         mat4::Mat4,
         obj_parser::OBJParser,
@@ -17,7 +20,10 @@ use crate::{
     },
 };
 
-use super::material::{deserialize_material, Material};
+use super::{
+    csg::{CsgNode, CsgOperation},
+    material::{deserialize_material, Emission, Material},
+};
 
 #[derive(Deserialize)]
 pub struct Sphere {
@@ -27,7 +33,7 @@ pub struct Sphere {
     radius: f32,
     #[serde(rename = "$value")]
     #[serde(deserialize_with = "deserialize_material")]
-    pub material: Rc<dyn Material>,
+    pub material: Arc<dyn Material>,
     #[serde(rename = "transform")]
     #[serde(default)]
     pub transformation_matrices: TransformationMatrices,
@@ -39,15 +45,205 @@ pub struct Mesh {
     pub name: String,
     #[serde(rename = "$value")]
     #[serde(deserialize_with = "deserialize_material")]
-    pub material: Rc<dyn Material>,
+    pub material: Arc<dyn Material>,
     #[serde(skip_deserializing)]
     pub obj_parser: OBJParser,
+    #[serde(skip_deserializing)]
+    pub triangle_bvh: TriangleBvh,
+    // Running sum of the world space triangle areas, only built for emissive meshes
+    #[serde(skip_deserializing)]
+    cumulative_areas: Vec<f32>,
+    #[serde(rename = "transform")]
+    #[serde(default)]
+    pub transformation_matrices: TransformationMatrices,
+}
+
+#[derive(Deserialize)]
+pub struct Plane {
+    #[serde(deserialize_with = "deserialize_point")]
+    position: Point,
+    #[serde(deserialize_with = "deserialize_vector")]
+    normal: Vec3,
+    // World units covered by one repetition of the texture
+    #[serde(rename = "@texture_scale")]
+    #[serde(default = "default_texture_scale")]
+    #[serde(deserialize_with = "deserialize_texture_scale")]
+    texture_scale: f32,
+    #[serde(rename = "$value")]
+    #[serde(deserialize_with = "deserialize_material")]
+    pub material: Arc<dyn Material>,
+    #[serde(rename = "transform")]
+    #[serde(default)]
+    pub transformation_matrices: TransformationMatrices,
+}
+
+fn default_texture_scale() -> f32 {
+    1.0
+}
+
+// The texture coordinates are divided by the scale
+fn deserialize_texture_scale<'de, D>(deserializer: D) -> Result<f32, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let texture_scale = f32::deserialize(deserializer)?;
+    if texture_scale > 0.0 {
+        Ok(texture_scale)
+    } else {
+        Err(D::Error::custom(format!(
+            "Plane requires a positive texture_scale, {} was given",
+            texture_scale
+        )))
+    }
+}
+
+#[derive(Deserialize)]
+pub struct Cuboid {
+    // Center of the box
+    #[serde(deserialize_with = "deserialize_point")]
+    position: Point,
+    #[serde(deserialize_with = "deserialize_vector")]
+    size: Vec3,
+    #[serde(rename = "$value")]
+    #[serde(deserialize_with = "deserialize_material")]
+    pub material: Arc<dyn Material>,
     #[serde(rename = "transform")]
     #[serde(default)]
     pub transformation_matrices: TransformationMatrices,
 }
 
+// Cylinder and cone stand on their base at `position` and extend along the y-axis
+#[derive(Deserialize)]
+pub struct Cylinder {
+    #[serde(deserialize_with = "deserialize_point")]
+    position: Point,
+    #[serde(rename = "@radius")]
+    radius: f32,
+    #[serde(rename = "@height")]
+    height: f32,
+    #[serde(rename = "$value")]
+    #[serde(deserialize_with = "deserialize_material")]
+    pub material: Arc<dyn Material>,
+    #[serde(rename = "transform")]
+    #[serde(default)]
+    pub transformation_matrices: TransformationMatrices,
+}
+
+#[derive(Deserialize)]
+pub struct Cone {
+    #[serde(deserialize_with = "deserialize_point")]
+    position: Point,
+    #[serde(rename = "@radius")]
+    radius: f32,
+    #[serde(rename = "@height")]
+    height: f32,
+    #[serde(rename = "$value")]
+    #[serde(deserialize_with = "deserialize_material")]
+    pub material: Arc<dyn Material>,
+    #[serde(rename = "transform")]
+    #[serde(default)]
+    pub transformation_matrices: TransformationMatrices,
+}
+
+#[derive(Deserialize)]
+pub struct Disk {
+    #[serde(deserialize_with = "deserialize_point")]
+    position: Point,
+    #[serde(deserialize_with = "deserialize_vector")]
+    normal: Vec3,
+    #[serde(rename = "@radius")]
+    radius: f32,
+    #[serde(rename = "$value")]
+    #[serde(deserialize_with = "deserialize_material")]
+    pub material: Arc<dyn Material>,
+    #[serde(rename = "transform")]
+    #[serde(default)]
+    pub transformation_matrices: TransformationMatrices,
+}
+
+// Intersection of an object space ray with an analytic primitive
+struct ObjectSpaceHit {
+    t: f32,
+    outward_normal: Vec3,
+    texture_coordinate: Vec3,
+    // Change of the object space point per unit of the texture coordinates u and v
+    texture_tangents: (Vec3, Vec3),
+}
+
+// Primitives which are intersected in object space, the transformation into world space
+// and the selection of the closest hit are shared between them
+trait AnalyticSurface: Send + Sync {
+    // All intersections with the line of the object space ray, sorted by t
+    fn object_space_hits(&self, ray: &Ray) -> Vec<ObjectSpaceHit>;
+
+    fn object_space_bounds(&self) -> Aabb;
+
+    fn transformation_matrices(&self) -> &TransformationMatrices;
+
+    fn material(&self) -> &Arc<dyn Material>;
+
+    // Uniformly distributed random point on the object space surface
+    fn object_space_sample(&self, rng: &mut dyn RngCore) -> SurfaceSample;
+}
+
+impl<T: AnalyticSurface> Hittable for T {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32, hit_record: &mut HitRecord) -> bool {
+        let transformed_ray = self
+            .transformation_matrices()
+            .transform_ray_to_object_space(ray);
+
+        match ObjectSpaceHit::closest_in_range(
+            self.object_space_hits(&transformed_ray),
+            t_min,
+            t_max,
+        ) {
+            Some(closest_hit) => {
+                closest_hit.record(
+                    ray,
+                    self.transformation_matrices(),
+                    self.material(),
+                    hit_record,
+                );
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        Some(
+            self.transformation_matrices()
+                .transform_bounding_box(&self.object_space_bounds()),
+        )
+    }
+
+    fn intervals(&self, ray: &Ray) -> Vec<Interval> {
+        let transformed_ray = self
+            .transformation_matrices()
+            .transform_ray_to_object_space(ray);
+
+        ObjectSpaceHit::convex_intervals(
+            &self.object_space_hits(&transformed_ray),
+            ray,
+            self.transformation_matrices(),
+            self.material(),
+        )
+    }
+
+    fn get_emission(&self) -> Option<Emission> {
+        self.material().get_emission()
+    }
+
+    fn sample_surface(&self, rng: &mut dyn RngCore) -> Option<SurfaceSample> {
+        Some(
+            self.transformation_matrices()
+                .transform_surface_sample(&self.object_space_sample(rng)),
+        )
+    }
+}
+
 pub struct TransformationMatrices {
+    pub object_to_world_matrix: Mat4,
     pub world_to_object_matrix: Mat4,
     pub normal_matrix: Mat4,
 }
@@ -55,140 +251,385 @@ pub struct TransformationMatrices {
 impl Default for TransformationMatrices {
     fn default() -> Self {
         Self {
+            object_to_world_matrix: Mat4::create(),
             world_to_object_matrix: Mat4::create(),
             normal_matrix: Mat4::create(),
         }
     }
 }
 
-impl Hittable for Mesh {
-    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32, hit_record: &mut HitRecord) -> bool {
-        const CORRECTION: f32 = 0.00001;
-        let transformed_ray = Ray::from_values(
-            &self
-                .transformation_matrices
-                .world_to_object_matrix
-                .transform_point3(&ray.origin),
-            &self
-                .transformation_matrices
-                .world_to_object_matrix
-                .transform_vec3(&ray.direction),
+impl TransformationMatrices {
+    pub fn transform_ray_to_object_space(&self, ray: &Ray) -> Ray {
+        Ray::from_values(
+            &self.world_to_object_matrix.transform_point3(&ray.origin),
+            &self.world_to_object_matrix.transform_vec3(&ray.direction),
+        )
+    }
+
+    /// Moves a point sampled on the object space surface into world space. The area is exact
+    /// for rotations, translations and uniform scaling, for non-uniform scaling it is only
+    /// approximated.
+    pub fn transform_surface_sample(&self, object_space_sample: &SurfaceSample) -> SurfaceSample {
+        let area_scale = self
+            .object_to_world_matrix
+            .determinant_3x3()
+            .abs()
+            .powf(2.0 / 3.0);
+
+        SurfaceSample {
+            point: self
+                .object_to_world_matrix
+                .transform_point3(&object_space_sample.point),
+            normal: self
+                .normal_matrix
+                .transform_vec3(&object_space_sample.normal)
+                .unit_vector(),
+            area: object_space_sample.area * area_scale,
+        }
+    }
+
+    pub fn transform_texture_tangents(&self, texture_tangents: &(Vec3, Vec3)) -> (Vec3, Vec3) {
+        (
+            self.object_to_world_matrix
+                .transform_vec3(&texture_tangents.0),
+            self.object_to_world_matrix
+                .transform_vec3(&texture_tangents.1),
+        )
+    }
+
+    /// Transforms an object space bounding box into world space. The box is padded slightly
+    /// so that rounding errors of the transformation can't cull grazing hits.
+    pub fn transform_bounding_box(&self, object_space_box: &Aabb) -> Aabb {
+        const PADDING: f32 = 0.0001;
+        let world_space_box = object_space_box.transform(&self.object_to_world_matrix);
+        let padding = Vec3::from_values(PADDING, PADDING, PADDING);
+        Aabb::from_values(
+            &world_space_box.minimum - &padding,
+            &world_space_box.maximum + &padding,
+        )
+    }
+}
+
+impl Mesh {
+    /// Builds the triangle BVH, has to be called once after the OBJ data was extracted
+    pub fn build_acceleration_structure(&mut self) {
+        self.triangle_bvh = TriangleBvh::build(
+            &self.obj_parser.sorted_vertices,
+            &self.obj_parser.new_index_array,
         );
+    }
 
-        let mut intersection_occured = false;
+    /// Prepares the uniform sampling of points on the mesh, needed for emissive meshes
+    pub fn build_area_distribution(&mut self) {
+        let mut total_area = 0.0;
+        self.cumulative_areas = (0..self.obj_parser.new_index_array.len() / 3)
+            .map(|triangle_index| {
+                let [vertex_a, vertex_b, vertex_c] = self.world_space_triangle(triangle_index);
+                total_area += (&vertex_b - &vertex_a)
+                    .cross(&(&vertex_c - &vertex_a))
+                    .length()
+                    / 2.0;
+                total_area
+            })
+            .collect();
+    }
 
-        for chunk in self.obj_parser.new_index_array.chunks_exact(3) {
-            let vertex_a = self.obj_parser.sorted_vertices[chunk[0]];
-            let vertex_b = self.obj_parser.sorted_vertices[chunk[1]];
-            let vertex_c = self.obj_parser.sorted_vertices[chunk[2]];
+    fn world_space_triangle(&self, triangle_index: usize) -> [Point; 3] {
+        let chunk = &self.obj_parser.new_index_array[triangle_index * 3..triangle_index * 3 + 3];
+        [chunk[0], chunk[1], chunk[2]].map(|vertex_index| {
+            self.transformation_matrices
+                .object_to_world_matrix
+                .transform_point3(&self.obj_parser.sorted_vertices[vertex_index])
+        })
+    }
 
-            let normal_a = self.obj_parser.sorted_normals[chunk[0]];
-            let normal_b = self.obj_parser.sorted_normals[chunk[1]];
-            let normal_c = self.obj_parser.sorted_normals[chunk[2]];
+    // Möller-Trumbore intersection of the object space ray with a single triangle,
+    // returns t and the barycentric coordinates u and v
+    fn intersect_triangle(
+        &self,
+        triangle_index: usize,
+        transformed_ray: &Ray,
+        t_min: f32,
+        t_max: f32,
+    ) -> Option<(f32, f32, f32)> {
+        const CORRECTION: f32 = 0.00001;
+        let chunk = &self.obj_parser.new_index_array[triangle_index * 3..triangle_index * 3 + 3];
 
-            let texture_vertex_1 = self.obj_parser.texture_vertices_to_be_returned[chunk[0]];
-            let texture_vertex_2 = self.obj_parser.texture_vertices_to_be_returned[chunk[1]];
-            let texture_vertex_3 = self.obj_parser.texture_vertices_to_be_returned[chunk[2]];
+        let vertex_a = self.obj_parser.sorted_vertices[chunk[0]];
+        let vertex_b = self.obj_parser.sorted_vertices[chunk[1]];
+        let vertex_c = self.obj_parser.sorted_vertices[chunk[2]];
 
-            let edge_ab = &vertex_b - &vertex_a;
-            let edge_ac = &vertex_c - &vertex_a;
+        let edge_ab = &vertex_b - &vertex_a;
+        let edge_ac = &vertex_c - &vertex_a;
 
-            let p_vec = transformed_ray.direction.cross(&edge_ab);
-            let determinant = edge_ac.dot(&p_vec);
+        let p_vec = transformed_ray.direction.cross(&edge_ab);
+        let determinant = edge_ac.dot(&p_vec);
 
-            // If dot product of ray direction and triangle normal is 0 then the ray and the triangle are parallel
-            // and there's no intersection
-            if determinant > -CORRECTION && determinant < CORRECTION {
-                continue;
-            }
+        // If dot product of ray direction and triangle normal is 0 then the ray and the triangle are parallel
+        // and there's no intersection
+        if determinant > -CORRECTION && determinant < CORRECTION {
+            return None;
+        }
 
-            // Check barycentric coordinates
+        // Check barycentric coordinates
 
-            let inverse_determinant = 1.0 / determinant;
-            let t_vec = &transformed_ray.origin - &vertex_a;
-            let u = inverse_determinant * t_vec.dot(&p_vec);
+        let inverse_determinant = 1.0 / determinant;
+        let t_vec = &transformed_ray.origin - &vertex_a;
+        let u = inverse_determinant * t_vec.dot(&p_vec);
 
-            if u < 0.0 || u > 1.0 {
-                continue;
-            }
+        if !(0.0..=1.0).contains(&u) {
+            return None;
+        }
 
-            let q_vec = t_vec.cross(&edge_ac);
-            let v = inverse_determinant * transformed_ray.direction.dot(&q_vec);
+        let q_vec = t_vec.cross(&edge_ac);
+        let v = inverse_determinant * transformed_ray.direction.dot(&q_vec);
 
-            if v < 0.0 || u + v > 1.0 {
-                continue;
-            }
+        if v < 0.0 || u + v > 1.0 {
+            return None;
+        }
 
-            let t = inverse_determinant * edge_ab.dot(&q_vec);
+        let t = inverse_determinant * edge_ab.dot(&q_vec);
 
-            if t < t_min || t > t_max {
-                continue;
-            }
+        if t < t_min || t > t_max {
+            return None;
+        }
 
-            if hit_record.t > 0.0 && hit_record.t < t {
-                continue;
-            }
+        Some((t, u, v))
+    }
 
-            intersection_occured = true;
+    // Fills the hit record from the triangle index and the barycentric coordinates of a hit
+    fn record_triangle_hit(
+        &self,
+        (triangle_index, t, u, v): (usize, f32, f32, f32),
+        ray: &Ray,
+        hit_record: &mut HitRecord,
+    ) {
+        let chunk = &self.obj_parser.new_index_array[triangle_index * 3..triangle_index * 3 + 3];
 
-            hit_record.t = t;
-            hit_record.point = ray.at(hit_record.t);
-            hit_record.material = self.material.clone();
+        let normal_a = self.obj_parser.sorted_normals[chunk[0]];
+        let normal_b = self.obj_parser.sorted_normals[chunk[1]];
+        let normal_c = self.obj_parser.sorted_normals[chunk[2]];
 
-            let outward_normal =
-                &(&(&u * &normal_c) + &(&v * &normal_b)) + &(&(1.0 - u - v) * &normal_a);
-            let texture_coordinate = &(&(&u * &texture_vertex_3) + &(&v * &texture_vertex_2))
-                + &(&(1.0 - u - v) * &texture_vertex_1);
+        hit_record.t = t;
+        hit_record.point = ray.at(hit_record.t);
+        hit_record.material = self.material.clone();
 
-            hit_record.set_face_normal(
-                &transformed_ray,
-                &self
-                    .transformation_matrices
-                    .normal_matrix
-                    .transform_vec3(&outward_normal)
-                    .unit_vector(),
-            );
-            hit_record.set_texture_coordinate(&texture_coordinate);
+        let outward_normal =
+            &(&(&u * &normal_c) + &(&v * &normal_b)) + &(&(1.0 - u - v) * &normal_a);
+
+        hit_record.set_face_normal(
+            ray,
+            &self
+                .transformation_matrices
+                .normal_matrix
+                .transform_vec3(&outward_normal)
+                .unit_vector(),
+        );
+        hit_record.set_texture_coordinate(&self.triangle_texture_coordinate(triangle_index, u, v));
+        hit_record.set_texture_tangents(self.triangle_texture_tangents(triangle_index).map(
+            |texture_tangents| {
+                self.transformation_matrices
+                    .transform_texture_tangents(&texture_tangents)
+            },
+        ));
+    }
 
-            //return true;
+    // Object space change of the point per unit of the texture coordinates, solved from the
+    // edges of the triangle in position and texture space
+    fn triangle_texture_tangents(&self, triangle_index: usize) -> Option<(Vec3, Vec3)> {
+        let chunk = &self.obj_parser.new_index_array[triangle_index * 3..triangle_index * 3 + 3];
+
+        let vertex_1 = self.obj_parser.sorted_vertices[chunk[0]];
+        let edge_1 = &self.obj_parser.sorted_vertices[chunk[1]] - &vertex_1;
+        let edge_2 = &self.obj_parser.sorted_vertices[chunk[2]] - &vertex_1;
+
+        let texture_vertex_1 = self.obj_parser.texture_vertices_to_be_returned[chunk[0]];
+        let texture_edge_1 =
+            &self.obj_parser.texture_vertices_to_be_returned[chunk[1]] - &texture_vertex_1;
+        let texture_edge_2 =
+            &self.obj_parser.texture_vertices_to_be_returned[chunk[2]] - &texture_vertex_1;
+
+        let determinant =
+            texture_edge_1.x() * texture_edge_2.y() - texture_edge_1.y() * texture_edge_2.x();
+        if determinant.abs() < 1e-12 {
+            return None;
         }
 
-        //false
-        intersection_occured
+        let u_tangent =
+            &(&(&texture_edge_2.y() * &edge_1) - &(&texture_edge_1.y() * &edge_2)) / &determinant;
+        let v_tangent =
+            &(&(&texture_edge_1.x() * &edge_2) - &(&texture_edge_2.x() * &edge_1)) / &determinant;
+        Some((u_tangent, v_tangent))
+    }
+
+    fn triangle_texture_coordinate(&self, triangle_index: usize, u: f32, v: f32) -> Vec3 {
+        let chunk = &self.obj_parser.new_index_array[triangle_index * 3..triangle_index * 3 + 3];
+
+        let texture_vertex_1 = self.obj_parser.texture_vertices_to_be_returned[chunk[0]];
+        let texture_vertex_2 = self.obj_parser.texture_vertices_to_be_returned[chunk[1]];
+        let texture_vertex_3 = self.obj_parser.texture_vertices_to_be_returned[chunk[2]];
+
+        &(&(&u * &texture_vertex_3) + &(&v * &texture_vertex_2))
+            + &(&(1.0 - u - v) * &texture_vertex_1)
+    }
+
+    // Intersection which skips cut out parts of the triangle
+    fn intersect_opaque_triangle(
+        &self,
+        triangle_index: usize,
+        transformed_ray: &Ray,
+        t_min: f32,
+        t_max: f32,
+    ) -> Option<(f32, f32, f32)> {
+        let (t, u, v) = self.intersect_triangle(triangle_index, transformed_ray, t_min, t_max)?;
+        if self
+            .material
+            .is_cut_out(&self.triangle_texture_coordinate(triangle_index, u, v))
+        {
+            return None;
+        }
+        Some((t, u, v))
     }
 }
 
-impl Hittable for Sphere {
+impl Hittable for Mesh {
     fn hit(&self, ray: &Ray, t_min: f32, t_max: f32, hit_record: &mut HitRecord) -> bool {
-        let transformed_ray = Ray::from_values(
-            &self
-                .transformation_matrices
-                .world_to_object_matrix
-                .transform_point3(&ray.origin),
-            &self
-                .transformation_matrices
-                .world_to_object_matrix
-                .transform_vec3(&ray.direction),
+        let transformed_ray = self
+            .transformation_matrices
+            .transform_ray_to_object_space(ray);
+
+        let mut closest_triangle = None;
+
+        self.triangle_bvh.hit(
+            &transformed_ray,
+            t_min,
+            t_max,
+            |triangle_index, t_min, t_max| {
+                let (t, u, v) =
+                    self.intersect_opaque_triangle(triangle_index, &transformed_ray, t_min, t_max)?;
+                closest_triangle = Some((triangle_index, t, u, v));
+                Some(t)
+            },
+        );
+
+        let Some(closest_triangle) = closest_triangle else {
+            return false;
+        };
+
+        self.record_triangle_hit(closest_triangle, ray, hit_record);
+
+        true
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        self.triangle_bvh.bounding_box().map(|object_space_box| {
+            self.transformation_matrices
+                .transform_bounding_box(&object_space_box)
+        })
+    }
+
+    fn shadow_check(&self, ray: &Ray, t_min: f32, t_max: f32) -> bool {
+        let transformed_ray = self
+            .transformation_matrices
+            .transform_ray_to_object_space(ray);
+
+        self.triangle_bvh.any_hit(
+            &transformed_ray,
+            t_min,
+            t_max,
+            |triangle_index, t_min, t_max| {
+                self.intersect_opaque_triangle(triangle_index, &transformed_ray, t_min, t_max)
+                    .is_some()
+            },
+        )
+    }
+
+    // Treats the mesh as closed, every pair of consecutive crossings forms one interval
+    fn intervals(&self, ray: &Ray) -> Vec<Interval> {
+        let transformed_ray = self
+            .transformation_matrices
+            .transform_ray_to_object_space(ray);
+
+        let mut triangle_hits = vec![];
+        self.triangle_bvh.any_hit(
+            &transformed_ray,
+            f32::NEG_INFINITY,
+            f32::INFINITY,
+            |triangle_index, t_min, t_max| {
+                if let Some((t, u, v)) =
+                    self.intersect_triangle(triangle_index, &transformed_ray, t_min, t_max)
+                {
+                    triangle_hits.push((triangle_index, t, u, v));
+                }
+                false
+            },
         );
+        triangle_hits.sort_by(|a, b| a.1.total_cmp(&b.1));
+
+        triangle_hits
+            .chunks_exact(2)
+            .map(|crossings| {
+                let mut interval = Interval {
+                    enter: HitRecord::new(),
+                    exit: HitRecord::new(),
+                };
+                self.record_triangle_hit(crossings[0], ray, &mut interval.enter);
+                self.record_triangle_hit(crossings[1], ray, &mut interval.exit);
+                interval
+            })
+            .collect()
+    }
+
+    fn get_emission(&self) -> Option<Emission> {
+        self.material.get_emission()
+    }
 
+    fn sample_surface(&self, rng: &mut dyn RngCore) -> Option<SurfaceSample> {
+        let total_area = *self.cumulative_areas.last()?;
+
+        // Triangles are chosen proportional to their area, the point uniformly within the triangle
+        let target_area = rng.gen::<f32>() * total_area;
+        let triangle_index = self
+            .cumulative_areas
+            .partition_point(|&area| area < target_area)
+            .min(self.cumulative_areas.len() - 1);
+        let [vertex_a, vertex_b, vertex_c] = self.world_space_triangle(triangle_index);
+
+        let (mut u, mut v) = (rng.gen::<f32>(), rng.gen::<f32>());
+        if u + v > 1.0 {
+            (u, v) = (1.0 - u, 1.0 - v);
+        }
+        let edge_ab = &vertex_b - &vertex_a;
+        let edge_ac = &vertex_c - &vertex_a;
+
+        Some(SurfaceSample {
+            point: &vertex_a + &(&(&u * &edge_ab) + &(&v * &edge_ac)),
+            normal: edge_ab.cross(&edge_ac).unit_vector(),
+            area: total_area,
+        })
+    }
+}
+
+impl Sphere {
+    fn roots(&self, transformed_ray: &Ray) -> Option<(f32, f32)> {
         let oc = &transformed_ray.origin - &self.position;
         let a = transformed_ray.direction.length_squared();
         let half_b = Vec3::dot(&oc, &transformed_ray.direction);
         let c = oc.length_squared() - self.radius * self.radius;
         let discriminant = half_b * half_b - a * c;
         if discriminant < 0.0 {
-            return false;
+            return None;
         }
 
         let sqrt_discriminant = f32::sqrt(discriminant);
 
-        let mut root = (-half_b - sqrt_discriminant) / a;
-        if root < t_min || root > t_max {
-            root = (-half_b + sqrt_discriminant) / a;
-            if root < t_min || root > t_max {
-                return false;
-            }
-        }
+        Some((
+            (-half_b - sqrt_discriminant) / a,
+            (-half_b + sqrt_discriminant) / a,
+        ))
+    }
+
+    fn record_hit(&self, root: f32, ray: &Ray, transformed_ray: &Ray, hit_record: &mut HitRecord) {
         hit_record.t = root;
         hit_record.point = ray.at(hit_record.t);
         let outward_normal = (&(&transformed_ray.at(hit_record.t) - &self.position)) / &self.radius;
@@ -199,10 +640,45 @@ impl Hittable for Sphere {
             .transform_vec3(&outward_normal)
             .unit_vector();
 
-        hit_record.set_face_normal(&transformed_ray, &transformed_outward_normal);
+        hit_record.set_face_normal(ray, &transformed_outward_normal);
 
         hit_record.material = self.material.clone();
 
+        hit_record.set_texture_coordinate(&self.texture_coordinate(transformed_ray, root));
+
+        // Derivatives of the longitude and latitude mapping, scaled by the world space radius.
+        // Both are unknown at the poles where all longitudes meet.
+        let normal = transformed_outward_normal;
+        let cos_latitude = (1.0 - normal.y() * normal.y()).max(0.0).sqrt();
+        let world_radius = (&hit_record.point
+            - &self
+                .transformation_matrices
+                .object_to_world_matrix
+                .transform_point3(&self.position))
+            .length();
+        hit_record.set_texture_tangents((cos_latitude > 0.0001).then(|| {
+            let pi = std::f32::consts::PI;
+            (
+                &(2.0 * pi * world_radius) * &Vec3::from_values(normal.z(), 0.0, -normal.x()),
+                &(pi * world_radius)
+                    * &Vec3::from_values(
+                        normal.y() * normal.x() / cos_latitude,
+                        -cos_latitude,
+                        normal.y() * normal.z() / cos_latitude,
+                    ),
+            )
+        }));
+    }
+
+    // Longitude and latitude of the world space normal
+    fn texture_coordinate(&self, transformed_ray: &Ray, root: f32) -> Vec3 {
+        let outward_normal = (&(&transformed_ray.at(root) - &self.position)) / &self.radius;
+        let transformed_outward_normal = self
+            .transformation_matrices
+            .normal_matrix
+            .transform_vec3(&outward_normal)
+            .unit_vector();
+
         let u = 0.5
             + f32::atan2(
                 transformed_outward_normal.x(),
@@ -210,18 +686,903 @@ impl Hittable for Sphere {
             ) / (2.0 * std::f32::consts::PI);
         let v = 0.5 - f32::asin(transformed_outward_normal.y()) / std::f32::consts::PI;
 
-        hit_record.set_texture_coordinate(&Vec3::from_values(u, v, 1.0));
+        Vec3::from_values(u, v, 1.0)
+    }
+}
+
+impl Hittable for Sphere {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32, hit_record: &mut HitRecord) -> bool {
+        let transformed_ray = self
+            .transformation_matrices
+            .transform_ray_to_object_space(ray);
+
+        let Some((near_root, far_root)) = self.roots(&transformed_ray) else {
+            return false;
+        };
+
+        // The far side is visible through cut out parts of the near side
+        for root in [near_root, far_root] {
+            if root < t_min || root > t_max {
+                continue;
+            }
+
+            if self
+                .material
+                .is_cut_out(&self.texture_coordinate(&transformed_ray, root))
+            {
+                continue;
+            }
+
+            self.record_hit(root, ray, &transformed_ray, hit_record);
+            return true;
+        }
+
+        false
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        let radius_vector = Vec3::from_values(self.radius, self.radius, self.radius);
+        Some(
+            self.transformation_matrices
+                .transform_bounding_box(&Aabb::from_values(
+                    &self.position - &radius_vector,
+                    &self.position + &radius_vector,
+                )),
+        )
+    }
+
+    fn get_emission(&self) -> Option<Emission> {
+        self.material.get_emission()
+    }
+
+    fn sample_surface(&self, rng: &mut dyn RngCore) -> Option<SurfaceSample> {
+        let z = 1.0 - 2.0 * rng.gen::<f32>();
+        let ring_radius = (1.0 - z * z).max(0.0).sqrt();
+        let phi = 2.0 * std::f32::consts::PI * rng.gen::<f32>();
+        let outward_normal = Vec3::from_values(ring_radius * phi.cos(), ring_radius * phi.sin(), z);
+
+        Some(
+            self.transformation_matrices
+                .transform_surface_sample(&SurfaceSample {
+                    point: &self.position + &(&self.radius * &outward_normal),
+                    normal: outward_normal,
+                    area: 4.0 * std::f32::consts::PI * self.radius * self.radius,
+                }),
+        )
+    }
+
+    fn intervals(&self, ray: &Ray) -> Vec<Interval> {
+        let transformed_ray = self
+            .transformation_matrices
+            .transform_ray_to_object_space(ray);
+
+        let Some((near_root, far_root)) = self.roots(&transformed_ray) else {
+            return vec![];
+        };
+
+        let mut interval = Interval {
+            enter: HitRecord::new(),
+            exit: HitRecord::new(),
+        };
+        self.record_hit(near_root, ray, &transformed_ray, &mut interval.enter);
+        self.record_hit(far_root, ray, &transformed_ray, &mut interval.exit);
+
+        vec![interval]
+    }
+}
+
+/// Orthonormal tangent and bitangent spanning the plane with the given unit normal, used for
+/// the texture coordinates of planes and disks and for sampling points on disks
+pub fn tangent_space(normal: &Vec3) -> (Vec3, Vec3) {
+    let helper_axis = if normal.x().abs() > 0.9 {
+        Vec3::from_values(0.0, 1.0, 0.0)
+    } else {
+        Vec3::from_values(1.0, 0.0, 0.0)
+    };
+    let tangent = helper_axis.cross(normal).unit_vector();
+    let bitangent = normal.cross(&tangent);
+    (tangent, bitangent)
+}
+
+impl Hittable for Plane {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32, hit_record: &mut HitRecord) -> bool {
+        const CORRECTION: f32 = 0.00001;
+        let transformed_ray = self
+            .transformation_matrices
+            .transform_ray_to_object_space(ray);
+
+        let normal = self.normal.unit_vector();
+        let denominator = normal.dot(&transformed_ray.direction);
+
+        // Ray runs parallel to the plane
+        if denominator.abs() < CORRECTION {
+            return false;
+        }
+
+        let t = normal.dot(&(&self.position - &transformed_ray.origin)) / denominator;
+        if t < t_min || t > t_max {
+            return false;
+        }
+
+        hit_record.t = t;
+        hit_record.point = ray.at(hit_record.t);
+        hit_record.material = self.material.clone();
+
+        hit_record.set_face_normal(
+            ray,
+            &self
+                .transformation_matrices
+                .normal_matrix
+                .transform_vec3(&normal)
+                .unit_vector(),
+        );
+
+        let (tangent, bitangent) = tangent_space(&normal);
+        let offset = &transformed_ray.at(t) - &self.position;
+        hit_record.set_texture_coordinate(&Vec3::from_values(
+            offset.dot(&tangent) / self.texture_scale,
+            offset.dot(&bitangent) / self.texture_scale,
+            1.0,
+        ));
+        hit_record.set_texture_tangents(Some(
+            self.transformation_matrices.transform_texture_tangents(&(
+                &tangent * &self.texture_scale,
+                &bitangent * &self.texture_scale,
+            )),
+        ));
+
+        true
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        // Infinite extent, tested outside of the BVH
+        None
+    }
+
+    // The plane bounds the half-space behind its normal, the open end of the interval has
+    // an infinite t and is never reported as a hit
+    fn intervals(&self, ray: &Ray) -> Vec<Interval> {
+        let transformed_ray = self
+            .transformation_matrices
+            .transform_ray_to_object_space(ray);
+        let normal = self.normal.unit_vector();
+
+        let mut boundary = HitRecord::new();
+        let mut open_end = HitRecord::new();
+        if !self.hit(ray, f32::NEG_INFINITY, f32::INFINITY, &mut boundary) {
+            // Parallel ray, either completely inside or outside
+            let offset = &transformed_ray.origin - &self.position;
+            if offset.dot(&normal) > 0.0 {
+                return vec![];
+            }
+            let mut enter = HitRecord::new();
+            enter.t = f32::NEG_INFINITY;
+            open_end.t = f32::INFINITY;
+            return vec![Interval {
+                enter,
+                exit: open_end,
+            }];
+        }
 
-        return true;
+        if normal.dot(&transformed_ray.direction) < 0.0 {
+            open_end.t = f32::INFINITY;
+            vec![Interval {
+                enter: boundary,
+                exit: open_end,
+            }]
+        } else {
+            open_end.t = f32::NEG_INFINITY;
+            vec![Interval {
+                enter: open_end,
+                exit: boundary,
+            }]
+        }
     }
 }
 
+impl ObjectSpaceHit {
+    // Hits of convex primitives, sorted by t, only form a single interval from the first to the
+    // last. The single hit of a disk forms an interval of zero thickness, so that the disk
+    // still shows up in unions and intersections.
+    fn convex_intervals(
+        object_space_hits: &[ObjectSpaceHit],
+        ray: &Ray,
+        transformation_matrices: &TransformationMatrices,
+        material: &Arc<dyn Material>,
+    ) -> Vec<Interval> {
+        if object_space_hits.is_empty() {
+            return vec![];
+        }
+
+        let mut interval = Interval {
+            enter: HitRecord::new(),
+            exit: HitRecord::new(),
+        };
+        object_space_hits[0].record(ray, transformation_matrices, material, &mut interval.enter);
+        object_space_hits[object_space_hits.len() - 1].record(
+            ray,
+            transformation_matrices,
+            material,
+            &mut interval.exit,
+        );
+
+        vec![interval]
+    }
+
+    fn closest_in_range(
+        object_space_hits: Vec<ObjectSpaceHit>,
+        t_min: f32,
+        t_max: f32,
+    ) -> Option<ObjectSpaceHit> {
+        object_space_hits
+            .into_iter()
+            .filter(|object_space_hit| object_space_hit.t >= t_min && object_space_hit.t <= t_max)
+            .min_by(|a, b| a.t.total_cmp(&b.t))
+    }
+
+    fn record(
+        &self,
+        ray: &Ray,
+        transformation_matrices: &TransformationMatrices,
+        material: &Arc<dyn Material>,
+        hit_record: &mut HitRecord,
+    ) {
+        hit_record.t = self.t;
+        hit_record.point = ray.at(hit_record.t);
+        hit_record.material = material.clone();
+        hit_record.set_face_normal(
+            ray,
+            &transformation_matrices
+                .normal_matrix
+                .transform_vec3(&self.outward_normal)
+                .unit_vector(),
+        );
+        hit_record.set_texture_coordinate(&self.texture_coordinate);
+        hit_record.set_texture_tangents(Some(
+            transformation_matrices.transform_texture_tangents(&self.texture_tangents),
+        ));
+    }
+}
+
+// Solves a*t^2 + 2*half_b*t + c = 0, returns the roots in ascending order
+fn solve_quadratic(a: f32, half_b: f32, c: f32) -> Option<(f32, f32)> {
+    const CORRECTION: f32 = 0.00001;
+    if a.abs() < CORRECTION {
+        return None;
+    }
+
+    let discriminant = half_b * half_b - a * c;
+    if discriminant < 0.0 {
+        return None;
+    }
+
+    // Avoids the cancellation of -half_b +- sqrt_discriminant, which causes acne for roots near zero
+    let q = -(half_b + half_b.signum() * f32::sqrt(discriminant));
+    if q == 0.0 {
+        return Some((0.0, 0.0));
+    }
+
+    let root_1 = q / a;
+    let root_2 = c / q;
+    Some((root_1.min(root_2), root_1.max(root_2)))
+}
+
+// Rays starting on a cylinder or cone (shadow rays) have c close to but rarely exactly zero,
+// which yields a spurious root just above t_min and results in acne. Snapping c to zero
+// turns that root into t = 0 so it gets rejected like for the other surfaces.
+fn snap_origin_to_surface(c: f32, radius: f32) -> f32 {
+    const ON_SURFACE_TOLERANCE: f32 = 0.0001;
+    if c.abs() < ON_SURFACE_TOLERANCE * radius * radius {
+        0.0
+    } else {
+        c
+    }
+}
+
+// Angle around the y-axis mapped to [0, 1], matches the sphere parameterization
+fn azimuth_texture_coordinate(x: f32, z: f32) -> f32 {
+    0.5 + f32::atan2(x, z) / (2.0 * std::f32::consts::PI)
+}
+
+// Change of a point around the y axis per unit of its azimuth texture coordinate
+fn azimuth_tangent(x: f32, z: f32) -> Vec3 {
+    &(2.0 * std::f32::consts::PI) * &Vec3::from_values(z, 0.0, -x)
+}
+
+// Uniformly distributed random point in a disk around the origin, returns its two coordinates
+fn random_point_in_disk(radius: f32, rng: &mut dyn RngCore) -> (f32, f32) {
+    let distance = radius * rng.gen::<f32>().sqrt();
+    let phi = 2.0 * std::f32::consts::PI * rng.gen::<f32>();
+    (distance * phi.cos(), distance * phi.sin())
+}
+
+// Hit with a cap disk of the given radius lying in the plane y = cap_height (object space)
+fn cap_hit(
+    ray: &Ray,
+    center: &Point,
+    cap_height: f32,
+    radius: f32,
+    outward_normal: Vec3,
+) -> Option<ObjectSpaceHit> {
+    const CORRECTION: f32 = 0.00001;
+    if ray.direction.y().abs() < CORRECTION {
+        return None;
+    }
+
+    let t = (center.y() + cap_height - ray.origin.y()) / ray.direction.y();
+    let local_point = &ray.at(t) - center;
+    if local_point.x().powi(2) + local_point.z().powi(2) > radius * radius {
+        return None;
+    }
+
+    Some(ObjectSpaceHit {
+        t,
+        outward_normal,
+        texture_coordinate: Vec3::from_values(
+            0.5 + local_point.x() / (2.0 * radius),
+            0.5 + local_point.z() / (2.0 * radius),
+            1.0,
+        ),
+        texture_tangents: (
+            Vec3::from_values(2.0 * radius, 0.0, 0.0),
+            Vec3::from_values(0.0, 0.0, 2.0 * radius),
+        ),
+    })
+}
+
+impl AnalyticSurface for Cuboid {
+    // Slab test which keeps track of the axis the ray enters and leaves through
+    fn object_space_hits(&self, ray: &Ray) -> Vec<ObjectSpaceHit> {
+        let half_size = &self.size * &0.5;
+        let minimum = &self.position - &half_size;
+        let maximum = &self.position + &half_size;
+
+        let mut t_enter = f32::NEG_INFINITY;
+        let mut t_exit = f32::INFINITY;
+        let mut enter_axis = 0;
+        let mut exit_axis = 0;
+
+        for axis in 0..3 {
+            if ray.direction[axis] == 0.0 {
+                if ray.origin[axis] < minimum[axis] || ray.origin[axis] > maximum[axis] {
+                    return vec![];
+                }
+                continue;
+            }
+
+            let inverse_direction = 1.0 / ray.direction[axis];
+            let mut t0 = (minimum[axis] - ray.origin[axis]) * inverse_direction;
+            let mut t1 = (maximum[axis] - ray.origin[axis]) * inverse_direction;
+            if inverse_direction < 0.0 {
+                std::mem::swap(&mut t0, &mut t1);
+            }
+
+            if t0 > t_enter {
+                t_enter = t0;
+                enter_axis = axis;
+            }
+            if t1 < t_exit {
+                t_exit = t1;
+                exit_axis = axis;
+            }
+            if t_exit < t_enter {
+                return vec![];
+            }
+        }
+
+        vec![
+            self.face_hit(ray, t_enter, enter_axis, &minimum),
+            self.face_hit(ray, t_exit, exit_axis, &minimum),
+        ]
+    }
+
+    fn object_space_bounds(&self) -> Aabb {
+        let half_size = &self.size * &0.5;
+        Aabb::from_values(&self.position - &half_size, &self.position + &half_size)
+    }
+
+    fn transformation_matrices(&self) -> &TransformationMatrices {
+        &self.transformation_matrices
+    }
+
+    fn material(&self) -> &Arc<dyn Material> {
+        &self.material
+    }
+
+    // A face is chosen proportional to its area, the point uniformly within the face
+    fn object_space_sample(&self, rng: &mut dyn RngCore) -> SurfaceSample {
+        let face_areas = [
+            self.size.y() * self.size.z(),
+            self.size.x() * self.size.z(),
+            self.size.x() * self.size.y(),
+        ];
+        let half_area = face_areas.iter().sum::<f32>();
+
+        let mut target_area = rng.gen::<f32>() * half_area;
+        let mut axis = 0;
+        while axis < 2 && target_area >= face_areas[axis] {
+            target_area -= face_areas[axis];
+            axis += 1;
+        }
+        let side = if rng.gen::<bool>() { 1.0 } else { -1.0 };
+
+        let mut offset = [0.0; 3];
+        let mut normal = [0.0; 3];
+        for (other_axis, value) in offset.iter_mut().enumerate() {
+            *value = (rng.gen::<f32>() - 0.5) * self.size[other_axis];
+        }
+        offset[axis] = side * 0.5 * self.size[axis];
+        normal[axis] = side;
+
+        SurfaceSample {
+            point: &self.position + &Vec3::from_values(offset[0], offset[1], offset[2]),
+            normal: Vec3::from_values(normal[0], normal[1], normal[2]),
+            area: 2.0 * half_area,
+        }
+    }
+}
+
+impl Cuboid {
+    fn face_hit(&self, ray: &Ray, t: f32, axis: usize, minimum: &Point) -> ObjectSpaceHit {
+        let point = ray.at(t);
+        let local_point = &point - minimum;
+
+        let mut normal = [0.0; 3];
+        normal[axis] = if point[axis] > self.position[axis] {
+            1.0
+        } else {
+            -1.0
+        };
+
+        // Each face is mapped to the full texture using the two remaining axes
+        let u_axis = if axis == 0 { 2 } else { 0 };
+        let v_axis = if axis == 1 { 2 } else { 1 };
+
+        ObjectSpaceHit {
+            t,
+            outward_normal: Vec3::from_values(normal[0], normal[1], normal[2]),
+            texture_coordinate: Vec3::from_values(
+                local_point[u_axis] / self.size[u_axis],
+                local_point[v_axis] / self.size[v_axis],
+                1.0,
+            ),
+            texture_tangents: (
+                axis_vector(u_axis, self.size[u_axis]),
+                axis_vector(v_axis, self.size[v_axis]),
+            ),
+        }
+    }
+}
+
+// Vector of the given length along one of the coordinate axes
+fn axis_vector(axis: usize, length: f32) -> Vec3 {
+    let mut components = [0.0; 3];
+    components[axis] = length;
+    Vec3::from_values(components[0], components[1], components[2])
+}
+
+impl AnalyticSurface for Cylinder {
+    fn object_space_hits(&self, ray: &Ray) -> Vec<ObjectSpaceHit> {
+        let mut object_space_hits = vec![];
+        let oc = &ray.origin - &self.position;
+
+        // Side: x^2 + z^2 = r^2 restricted to 0 <= y <= height
+        if let Some((root_1, root_2)) = solve_quadratic(
+            ray.direction.x().powi(2) + ray.direction.z().powi(2),
+            oc.x() * ray.direction.x() + oc.z() * ray.direction.z(),
+            snap_origin_to_surface(
+                oc.x().powi(2) + oc.z().powi(2) - self.radius * self.radius,
+                self.radius,
+            ),
+        ) {
+            for t in [root_1, root_2] {
+                let local_point = &ray.at(t) - &self.position;
+                if local_point.y() < 0.0 || local_point.y() > self.height {
+                    continue;
+                }
+
+                object_space_hits.push(ObjectSpaceHit {
+                    t,
+                    outward_normal: Vec3::from_values(
+                        local_point.x() / self.radius,
+                        0.0,
+                        local_point.z() / self.radius,
+                    ),
+                    texture_coordinate: Vec3::from_values(
+                        azimuth_texture_coordinate(local_point.x(), local_point.z()),
+                        1.0 - local_point.y() / self.height,
+                        1.0,
+                    ),
+                    texture_tangents: (
+                        azimuth_tangent(local_point.x(), local_point.z()),
+                        Vec3::from_values(0.0, -self.height, 0.0),
+                    ),
+                });
+            }
+        }
+
+        object_space_hits.extend(cap_hit(
+            ray,
+            &self.position,
+            0.0,
+            self.radius,
+            Vec3::from_values(0.0, -1.0, 0.0),
+        ));
+        object_space_hits.extend(cap_hit(
+            ray,
+            &self.position,
+            self.height,
+            self.radius,
+            Vec3::from_values(0.0, 1.0, 0.0),
+        ));
+
+        object_space_hits.sort_by(|a, b| a.t.total_cmp(&b.t));
+        object_space_hits
+    }
+
+    fn object_space_bounds(&self) -> Aabb {
+        Aabb::from_values(
+            &self.position - &Vec3::from_values(self.radius, 0.0, self.radius),
+            &self.position + &Vec3::from_values(self.radius, self.height, self.radius),
+        )
+    }
+
+    fn transformation_matrices(&self) -> &TransformationMatrices {
+        &self.transformation_matrices
+    }
+
+    fn material(&self) -> &Arc<dyn Material> {
+        &self.material
+    }
+
+    fn object_space_sample(&self, rng: &mut dyn RngCore) -> SurfaceSample {
+        let side_area = 2.0 * std::f32::consts::PI * self.radius * self.height;
+        let cap_area = std::f32::consts::PI * self.radius * self.radius;
+        let area = side_area + 2.0 * cap_area;
+
+        let target_area = rng.gen::<f32>() * area;
+        if target_area < side_area {
+            let phi = 2.0 * std::f32::consts::PI * rng.gen::<f32>();
+            let outward_normal = Vec3::from_values(phi.cos(), 0.0, phi.sin());
+            let offset = Vec3::from_values(
+                self.radius * outward_normal.x(),
+                rng.gen::<f32>() * self.height,
+                self.radius * outward_normal.z(),
+            );
+            return SurfaceSample {
+                point: &self.position + &offset,
+                normal: outward_normal,
+                area,
+            };
+        }
+
+        let (cap_height, normal_y) = if target_area < side_area + cap_area {
+            (0.0, -1.0)
+        } else {
+            (self.height, 1.0)
+        };
+        let (x, z) = random_point_in_disk(self.radius, rng);
+        SurfaceSample {
+            point: &self.position + &Vec3::from_values(x, cap_height, z),
+            normal: Vec3::from_values(0.0, normal_y, 0.0),
+            area,
+        }
+    }
+}
+
+impl AnalyticSurface for Cone {
+    fn object_space_hits(&self, ray: &Ray) -> Vec<ObjectSpaceHit> {
+        let mut object_space_hits = vec![];
+        let oc = &ray.origin - &self.position;
+
+        // Side: x^2 + z^2 = (k * (height - y))^2 with the slope k = radius / height
+        let slope_squared = (self.radius / self.height).powi(2);
+        let distance_to_apex = self.height - oc.y();
+
+        if let Some((root_1, root_2)) = solve_quadratic(
+            ray.direction.x().powi(2) + ray.direction.z().powi(2)
+                - slope_squared * ray.direction.y().powi(2),
+            oc.x() * ray.direction.x()
+                + oc.z() * ray.direction.z()
+                + slope_squared * distance_to_apex * ray.direction.y(),
+            snap_origin_to_surface(
+                oc.x().powi(2) + oc.z().powi(2) - slope_squared * distance_to_apex.powi(2),
+                self.radius,
+            ),
+        ) {
+            for t in [root_1, root_2] {
+                let local_point = &ray.at(t) - &self.position;
+                // Discard the mirrored cone above the apex
+                if local_point.y() < 0.0 || local_point.y() > self.height {
+                    continue;
+                }
+                let azimuth = f32::atan2(local_point.x(), local_point.z());
+
+                object_space_hits.push(ObjectSpaceHit {
+                    t,
+                    outward_normal: Vec3::from_values(
+                        local_point.x(),
+                        slope_squared * (self.height - local_point.y()),
+                        local_point.z(),
+                    )
+                    .unit_vector(),
+                    texture_coordinate: Vec3::from_values(
+                        azimuth_texture_coordinate(local_point.x(), local_point.z()),
+                        1.0 - local_point.y() / self.height,
+                        1.0,
+                    ),
+                    // Towards the apex the radius shrinks with the texture coordinate v
+                    texture_tangents: (
+                        azimuth_tangent(local_point.x(), local_point.z()),
+                        Vec3::from_values(
+                            self.radius * azimuth.sin(),
+                            -self.height,
+                            self.radius * azimuth.cos(),
+                        ),
+                    ),
+                });
+            }
+        }
+
+        object_space_hits.extend(cap_hit(
+            ray,
+            &self.position,
+            0.0,
+            self.radius,
+            Vec3::from_values(0.0, -1.0, 0.0),
+        ));
+
+        object_space_hits.sort_by(|a, b| a.t.total_cmp(&b.t));
+        object_space_hits
+    }
+
+    fn object_space_bounds(&self) -> Aabb {
+        Aabb::from_values(
+            &self.position - &Vec3::from_values(self.radius, 0.0, self.radius),
+            &self.position + &Vec3::from_values(self.radius, self.height, self.radius),
+        )
+    }
+
+    fn transformation_matrices(&self) -> &TransformationMatrices {
+        &self.transformation_matrices
+    }
+
+    fn material(&self) -> &Arc<dyn Material> {
+        &self.material
+    }
+
+    fn object_space_sample(&self, rng: &mut dyn RngCore) -> SurfaceSample {
+        let side_area = std::f32::consts::PI * self.radius * self.radius.hypot(self.height);
+        let base_area = std::f32::consts::PI * self.radius * self.radius;
+        let area = side_area + base_area;
+
+        if rng.gen::<f32>() * area >= side_area {
+            let (x, z) = random_point_in_disk(self.radius, rng);
+            return SurfaceSample {
+                point: &self.position + &Vec3::from_values(x, 0.0, z),
+                normal: Vec3::from_values(0.0, -1.0, 0.0),
+                area,
+            };
+        }
+
+        // The circumference grows linearly with the distance from the apex
+        let apex_distance = rng.gen::<f32>().sqrt();
+        let phi = 2.0 * std::f32::consts::PI * rng.gen::<f32>();
+        let ring_radius = self.radius * apex_distance;
+        SurfaceSample {
+            point: &self.position
+                + &Vec3::from_values(
+                    ring_radius * phi.cos(),
+                    self.height * (1.0 - apex_distance),
+                    ring_radius * phi.sin(),
+                ),
+            normal: Vec3::from_values(phi.cos(), self.radius / self.height, phi.sin())
+                .unit_vector(),
+            area,
+        }
+    }
+}
+
+impl AnalyticSurface for Disk {
+    fn object_space_hits(&self, ray: &Ray) -> Vec<ObjectSpaceHit> {
+        const CORRECTION: f32 = 0.00001;
+        let normal = self.normal.unit_vector();
+        let denominator = normal.dot(&ray.direction);
+
+        if denominator.abs() < CORRECTION {
+            return vec![];
+        }
+
+        let t = normal.dot(&(&self.position - &ray.origin)) / denominator;
+        let offset = &ray.at(t) - &self.position;
+        if offset.length_squared() > self.radius * self.radius {
+            return vec![];
+        }
+
+        let (tangent, bitangent) = tangent_space(&normal);
+        vec![ObjectSpaceHit {
+            t,
+            outward_normal: normal,
+            texture_coordinate: Vec3::from_values(
+                0.5 + offset.dot(&tangent) / (2.0 * self.radius),
+                0.5 + offset.dot(&bitangent) / (2.0 * self.radius),
+                1.0,
+            ),
+            texture_tangents: (
+                &tangent * &(2.0 * self.radius),
+                &bitangent * &(2.0 * self.radius),
+            ),
+        }]
+    }
+
+    fn object_space_bounds(&self) -> Aabb {
+        // Extent of a circle along each axis is radius * sin(angle between axis and normal)
+        let normal = self.normal.unit_vector();
+        let extent = Vec3::from_values(
+            self.radius * (1.0 - normal.x().powi(2)).max(0.0).sqrt(),
+            self.radius * (1.0 - normal.y().powi(2)).max(0.0).sqrt(),
+            self.radius * (1.0 - normal.z().powi(2)).max(0.0).sqrt(),
+        );
+        Aabb::from_values(&self.position - &extent, &self.position + &extent)
+    }
+
+    fn transformation_matrices(&self) -> &TransformationMatrices {
+        &self.transformation_matrices
+    }
+
+    fn material(&self) -> &Arc<dyn Material> {
+        &self.material
+    }
+
+    fn object_space_sample(&self, rng: &mut dyn RngCore) -> SurfaceSample {
+        let normal = self.normal.unit_vector();
+        let (tangent, bitangent) = tangent_space(&normal);
+        let (x, y) = random_point_in_disk(self.radius, rng);
+
+        SurfaceSample {
+            point: &self.position + &(&(&x * &tangent) + &(&y * &bitangent)),
+            normal,
+            area: std::f32::consts::PI * self.radius * self.radius,
+        }
+    }
+}
+
+// Only the deserialized description, the children are turned into a CsgNode
+#[derive(Deserialize)]
+struct Csg {
+    #[serde(rename = "@op")]
+    operation: CsgOperation,
+    #[serde(rename = "$value")]
+    children: Vec<Surface>,
+    #[serde(rename = "transform")]
+    #[serde(default)]
+    transformation_matrices: TransformationMatrices,
+}
+
 #[derive(Deserialize)]
 enum Surface {
     #[serde(rename = "sphere")]
     Sphere(Sphere),
     #[serde(rename = "mesh")]
-    Mesh(Mesh),
+    Mesh(Box<Mesh>),
+    #[serde(rename = "plane")]
+    Plane(Plane),
+    #[serde(rename = "box")]
+    Cuboid(Cuboid),
+    #[serde(rename = "cylinder")]
+    Cylinder(Cylinder),
+    #[serde(rename = "cone")]
+    Cone(Cone),
+    #[serde(rename = "disk")]
+    Disk(Disk),
+    #[serde(rename = "csg")]
+    Csg(Csg),
+}
+
+impl Surface {
+    // Emissive surfaces are sampled as area lights, which requires a bounded surface
+    fn check_emission(&self) -> Result<(), String> {
+        let is_emissive = self
+            .materials()
+            .iter()
+            .any(|material| material.get_emission().is_some());
+        match self {
+            Surface::Plane(_) if is_emissive => {
+                Err("Planes can't be emissive, use a disk or a box instead".to_string())
+            }
+            Surface::Csg(_) if is_emissive => {
+                Err("Surfaces inside of a csg can't be emissive".to_string())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // Materials in the order of the scene file
+    fn materials(&self) -> Vec<Arc<dyn Material>> {
+        match self {
+            Surface::Sphere(sphere) => vec![sphere.material.clone()],
+            Surface::Mesh(mesh) => vec![mesh.material.clone()],
+            Surface::Plane(plane) => vec![plane.material.clone()],
+            Surface::Cuboid(cuboid) => vec![cuboid.material.clone()],
+            Surface::Cylinder(cylinder) => vec![cylinder.material.clone()],
+            Surface::Cone(cone) => vec![cone.material.clone()],
+            Surface::Disk(disk) => vec![disk.material.clone()],
+            Surface::Csg(csg) => csg.children.iter().flat_map(Surface::materials).collect(),
+        }
+    }
+
+    fn into_hittable(self) -> Arc<dyn Hittable> {
+        match self {
+            Surface::Sphere(sphere) => Arc::new(sphere) as Arc<dyn Hittable>,
+            Surface::Mesh(mut mesh) => {
+                mesh.obj_parser.extract_data(
+                    &file_loader::load_obj_file(&mesh.name).expect("Reading of OBJ-File failed!"),
+                );
+                mesh.build_acceleration_structure();
+                if mesh.material.get_emission().is_some() {
+                    mesh.build_area_distribution();
+                }
+                Arc::new(*mesh) as Arc<dyn Hittable>
+            }
+            Surface::Plane(plane) => Arc::new(plane) as Arc<dyn Hittable>,
+            Surface::Cuboid(cuboid) => Arc::new(cuboid) as Arc<dyn Hittable>,
+            Surface::Cylinder(cylinder) => Arc::new(cylinder) as Arc<dyn Hittable>,
+            Surface::Cone(cone) => Arc::new(cone) as Arc<dyn Hittable>,
+            Surface::Disk(disk) => Arc::new(disk) as Arc<dyn Hittable>,
+            Surface::Csg(csg) => Arc::new(CsgNode::new(
+                csg.operation,
+                csg.children
+                    .into_iter()
+                    .map(Surface::into_hittable)
+                    .collect(),
+                csg.transformation_matrices,
+            )) as Arc<dyn Hittable>,
+        }
+    }
+}
+
+/// Top level surface of the scene, tags its hits with the index of the surface and of the hit
+/// material in the scene file
+struct IndexedSurface {
+    surface: Arc<dyn Hittable>,
+    object_index: usize,
+    materials: Vec<(usize, Arc<dyn Material>)>,
+}
+
+impl Hittable for IndexedSurface {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32, hit_record: &mut HitRecord) -> bool {
+        if !self.surface.hit(ray, t_min, t_max, hit_record) {
+            return false;
+        }
+
+        hit_record.object_index = Some(self.object_index);
+        hit_record.material_index = self
+            .materials
+            .iter()
+            .find(|(_, material)| Arc::ptr_eq(material, &hit_record.material))
+            .map(|(material_index, _)| *material_index);
+        true
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        self.surface.bounding_box()
+    }
+
+    fn shadow_check(&self, ray: &Ray, t_min: f32, t_max: f32) -> bool {
+        self.surface.shadow_check(ray, t_min, t_max)
+    }
+
+    fn intervals(&self, ray: &Ray) -> Vec<Interval> {
+        self.surface.intervals(ray)
+    }
+
+    fn get_emission(&self) -> Option<Emission> {
+        self.surface.get_emission()
+    }
+
+    fn sample_surface(&self, rng: &mut dyn RngCore) -> Option<SurfaceSample> {
+        self.surface.sample_surface(rng)
+    }
 }
 
 pub fn deserialize_surfaces<'de, D>(deserializer: D) -> Result<HittableList, D::Error>
@@ -238,20 +1599,28 @@ where
 
     let surfaces: Vec<Surface> = inner_surfaces.surfaces;
     let mut hittable_list = HittableList::new();
-
-    for surface in surfaces {
-        let hittable: Rc<dyn Hittable> = match surface {
-            Surface::Sphere(sphere) => Rc::new(sphere) as Rc<dyn Hittable>,
-            Surface::Mesh(mut mesh) => {
-                mesh.obj_parser.extract_data(
-                    &file_loader::load_obj_file(&mesh.name).expect("Reading of OBJ-File failed!"),
-                );
-                Rc::new(mesh) as Rc<dyn Hittable>
-            }
-        };
-        hittable_list.add(hittable);
+    let mut material_count = 0;
+
+    for (object_index, surface) in surfaces.into_iter().enumerate() {
+        surface.check_emission().map_err(D::Error::custom)?;
+
+        let materials: Vec<(usize, Arc<dyn Material>)> = surface
+            .materials()
+            .into_iter()
+            .enumerate()
+            .map(|(offset, material)| (material_count + offset, material))
+            .collect();
+        material_count += materials.len();
+
+        hittable_list.add(Arc::new(IndexedSurface {
+            surface: surface.into_hittable(),
+            object_index,
+            materials,
+        }));
     }
 
+    hittable_list.build_bvh();
+
     Ok(hittable_list)
 }
 
@@ -355,9 +1724,29 @@ pub enum TransformationEnum {
         #[serde(rename = "@theta")]
         angle: f32,
     },
+    #[serde(rename = "rotateZ")]
+    RotateZ {
+        #[serde(rename = "@theta")]
+        angle: f32,
+    },
+    // Rotation by theta degrees around the axis (x, y, z)
+    #[serde(rename = "rotate")]
+    Rotate {
+        #[serde(rename = "@theta")]
+        angle: f32,
+        #[serde(rename = "@x")]
+        x: f32,
+        #[serde(rename = "@y")]
+        y: f32,
+        #[serde(rename = "@z")]
+        z: f32,
+    },
     #[serde(rename = "scale")]
     #[serde(deserialize_with = "deserialize_vector")]
     Scale(Vec3),
+    #[serde(rename = "matrix")]
+    #[serde(deserialize_with = "deserialize_matrix")]
+    Matrix(Mat4),
 }
 
 impl<'de> Deserialize<'de> for TransformationMatrices {
@@ -377,13 +1766,17 @@ impl<'de> Deserialize<'de> for TransformationMatrices {
 
         let transform_operations: Vec<TransformationEnum> = inner_transformation.transformation;
 
-        let world_to_object_transform_matrix =
-            Mat4::create_world_to_object_transformation_matrix(&transform_operations);
+        let object_to_world_matrix =
+            Mat4::create_object_to_world_transformation_matrix(&transform_operations);
+        let world_to_object_matrix =
+            Mat4::create_world_to_object_transformation_matrix(&object_to_world_matrix)
+                .ok_or_else(|| D::Error::custom("Transformation is not invertible"))?;
 
         Ok(TransformationMatrices {
-            world_to_object_matrix: world_to_object_transform_matrix,
+            object_to_world_matrix,
+            world_to_object_matrix,
             normal_matrix: Mat4::create_normal_matrix_of_object_to_world_space(
-                &transform_operations,
+                &world_to_object_matrix,
             ),
         })
     }
@@ -0,0 +1,112 @@
+use crate::utils::{
+    mat4::Mat4,
+    vec3::{Point, Vec3},
+};
+
+use super::ray::Ray;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Aabb {
+    pub minimum: Point,
+    pub maximum: Point,
+}
+
+impl Aabb {
+    pub fn from_values(minimum: Point, maximum: Point) -> Self {
+        Self { minimum, maximum }
+    }
+
+    pub fn from_points(points: &[Point]) -> Self {
+        let mut minimum = Point::from_values(f32::INFINITY, f32::INFINITY, f32::INFINITY);
+        let mut maximum =
+            Point::from_values(f32::NEG_INFINITY, f32::NEG_INFINITY, f32::NEG_INFINITY);
+
+        for point in points {
+            minimum = Point::from_values(
+                minimum.x().min(point.x()),
+                minimum.y().min(point.y()),
+                minimum.z().min(point.z()),
+            );
+            maximum = Point::from_values(
+                maximum.x().max(point.x()),
+                maximum.y().max(point.y()),
+                maximum.z().max(point.z()),
+            );
+        }
+
+        Self { minimum, maximum }
+    }
+
+    pub fn surrounding_box(first: &Aabb, second: &Aabb) -> Aabb {
+        Aabb::from_points(&[first.minimum, first.maximum, second.minimum, second.maximum])
+    }
+
+    /// Box shared by both boxes, if they don't overlap the result is empty (minimum > maximum)
+    pub fn overlap(first: &Aabb, second: &Aabb) -> Aabb {
+        Aabb::from_values(
+            Point::from_values(
+                first.minimum.x().max(second.minimum.x()),
+                first.minimum.y().max(second.minimum.y()),
+                first.minimum.z().max(second.minimum.z()),
+            ),
+            Point::from_values(
+                first.maximum.x().min(second.maximum.x()),
+                first.maximum.y().min(second.maximum.y()),
+                first.maximum.z().min(second.maximum.z()),
+            ),
+        )
+    }
+
+    pub fn centroid(&self) -> Point {
+        &(&self.minimum + &self.maximum) * &0.5
+    }
+
+    pub fn extent(&self) -> Vec3 {
+        &self.maximum - &self.minimum
+    }
+
+    pub fn longest_axis(&self) -> usize {
+        let extent = self.extent();
+        if extent.x() > extent.y() && extent.x() > extent.z() {
+            0
+        } else if extent.y() > extent.z() {
+            1
+        } else {
+            2
+        }
+    }
+
+    /// Transforms all eight corners of the box and returns the box enclosing them.
+    pub fn transform(&self, matrix: &Mat4) -> Aabb {
+        let extremes = [self.minimum, self.maximum];
+        let corners: Vec<Point> = (0..8)
+            .map(|i| {
+                matrix.transform_point3(&Point::from_values(
+                    extremes[i & 1].x(),
+                    extremes[(i >> 1) & 1].y(),
+                    extremes[(i >> 2) & 1].z(),
+                ))
+            })
+            .collect();
+        Aabb::from_points(&corners)
+    }
+
+    /// Slab test, see "Ray Tracing: The Next Week" (Andrew Kensler's variant)
+    pub fn hit(&self, ray: &Ray, mut t_min: f32, mut t_max: f32) -> bool {
+        for axis in 0..3 {
+            let inverse_direction = 1.0 / ray.direction[axis];
+            let mut t0 = (self.minimum[axis] - ray.origin[axis]) * inverse_direction;
+            let mut t1 = (self.maximum[axis] - ray.origin[axis]) * inverse_direction;
+            if inverse_direction < 0.0 {
+                std::mem::swap(&mut t0, &mut t1);
+            }
+            // f32::max/min ignore NaN, which occurs for rays lying exactly in a slab plane
+            t_min = t_min.max(t0);
+            t_max = t_max.min(t1);
+            if t_max < t_min {
+                return false;
+            }
+        }
+        true
+    }
+}
@@ -0,0 +1,93 @@
+use std::sync::Arc;
+
+use super::{
+    aabb::Aabb,
+    hittable::{HitRecord, Hittable},
+    ray::Ray,
+};
+
+// Bounding volume hierarchy as described in "Ray Tracing: The Next Week".
+// Objects are split at the median of their centroids along the longest axis.
+pub struct BvhNode {
+    left: Arc<dyn Hittable>,
+    right: Arc<dyn Hittable>,
+    bounding_box: Aabb,
+}
+
+impl BvhNode {
+    /// Builds the hierarchy over bounded objects. A single object is returned as is,
+    /// so leaves never need to be tested twice.
+    pub fn build(objects: Vec<Arc<dyn Hittable>>) -> Arc<dyn Hittable> {
+        let bounded_objects = objects
+            .into_iter()
+            .map(|object| {
+                let bounding_box = object
+                    .bounding_box()
+                    .expect("Only bounded objects can be part of a BVH");
+                (object, bounding_box)
+            })
+            .collect();
+
+        BvhNode::build_recursive(bounded_objects)
+    }
+
+    fn build_recursive(mut objects: Vec<(Arc<dyn Hittable>, Aabb)>) -> Arc<dyn Hittable> {
+        if objects.len() == 1 {
+            return objects.pop().unwrap().0;
+        }
+
+        let centroid_bounds = Aabb::from_points(
+            &objects
+                .iter()
+                .map(|(_, bounding_box)| bounding_box.centroid())
+                .collect::<Vec<_>>(),
+        );
+        let axis = centroid_bounds.longest_axis();
+
+        objects.sort_by(|(_, a), (_, b)| a.centroid()[axis].total_cmp(&b.centroid()[axis]));
+
+        let bounding_box = objects
+            .iter()
+            .fold(objects[0].1, |accumulated, (_, bounding_box)| {
+                Aabb::surrounding_box(&accumulated, bounding_box)
+            });
+
+        let right_objects = objects.split_off(objects.len() / 2);
+
+        Arc::new(BvhNode {
+            left: BvhNode::build_recursive(objects),
+            right: BvhNode::build_recursive(right_objects),
+            bounding_box,
+        })
+    }
+}
+
+impl Hittable for BvhNode {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32, hit_record: &mut HitRecord) -> bool {
+        if !self.bounding_box.hit(ray, t_min, t_max) {
+            return false;
+        }
+
+        let hit_left = self.left.hit(ray, t_min, t_max, hit_record);
+        let hit_right = self.right.hit(
+            ray,
+            t_min,
+            if hit_left { hit_record.t } else { t_max },
+            hit_record,
+        );
+
+        hit_left || hit_right
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        Some(self.bounding_box)
+    }
+
+    fn shadow_check(&self, ray: &Ray, t_min: f32, t_max: f32) -> bool {
+        if !self.bounding_box.hit(ray, t_min, t_max) {
+            return false;
+        }
+
+        self.left.shadow_check(ray, t_min, t_max) || self.right.shadow_check(ray, t_min, t_max)
+    }
+}
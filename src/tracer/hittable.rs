@@ -1,11 +1,13 @@
-use std::rc::Rc;
+use std::sync::Arc;
+
+use rand::RngCore;
 
 use crate::{
-    scene::material::{Material, MaterialSolid},
+    scene::material::{Emission, Material, MaterialSolid},
     utils::vec3::{Point, Vec3},
 };
 
-use super::ray::Ray;
+use super::{aabb::Aabb, ray::Ray};
 
 #[derive(Clone)]
 pub struct HitRecord {
@@ -13,22 +15,37 @@ pub struct HitRecord {
     pub normal: Vec3,
     pub t: f32,
     pub front_face: bool,
-    pub material: Rc<dyn Material>,
+    pub material: Arc<dyn Material>,
     pub texture_coordinate: Option<Vec3>,
+    // Position of the hit surface and its material in the scene file, used by the AOV passes
+    pub object_index: Option<usize>,
+    pub material_index: Option<usize>,
+    // Change of the texture coordinate towards the neighbouring pixels to the right and
+    // above, only known for hits of camera rays on surfaces with mipmapped textures
+    pub texture_derivatives: Option<(Vec3, Vec3)>,
+    // Change of the hit point per unit of the texture coordinates u and v, not known for
+    // degenerate texture mappings (e.g. at the poles of a sphere)
+    pub texture_tangents: Option<(Vec3, Vec3)>,
 }
 
 impl HitRecord {
+    /// The ray has to be given in the same space as the normal, i.e. the ray which was passed to
+    /// `hit` and not the ray transformed into object space
     pub fn set_face_normal(&mut self, ray: &Ray, outward_normal: &Vec3) {
-        self.front_face = Vec3::dot(&ray.direction, &outward_normal) < 0.0;
-        self.normal = outward_normal.clone(); /*if self.front_face {
-                                                  outward_normal.clone()
-                                              } else {
-                                                  -outward_normal
-                                              }*/
+        self.front_face = Vec3::dot(&ray.direction, outward_normal) < 0.0;
+        self.normal = *outward_normal; /*if self.front_face {
+                                           outward_normal.clone()
+                                       } else {
+                                           -outward_normal
+                                       }*/
     }
 
     pub fn set_texture_coordinate(&mut self, texture_coordinate: &Vec3) {
-        self.texture_coordinate = Some(texture_coordinate.clone());
+        self.texture_coordinate = Some(*texture_coordinate);
+    }
+
+    pub fn set_texture_tangents(&mut self, texture_tangents: Option<(Vec3, Vec3)>) {
+        self.texture_tangents = texture_tangents;
     }
 
     pub fn new() -> Self {
@@ -37,12 +54,56 @@ impl HitRecord {
             normal: Vec3::from_values(0.0, 0.0, 0.0),
             t: 0.0,
             front_face: true,
-            material: Rc::new(MaterialSolid::new()),
+            material: Arc::new(MaterialSolid::new()),
             texture_coordinate: None,
+            object_index: None,
+            material_index: None,
+            texture_derivatives: None,
+            texture_tangents: None,
         }
     }
 }
 
-pub trait Hittable {
+/// Section of a ray which lies inside of a solid object
+#[derive(Clone)]
+pub struct Interval {
+    pub enter: HitRecord,
+    pub exit: HitRecord,
+}
+
+/// Point on the surface of an object in world space
+pub struct SurfaceSample {
+    pub point: Point,
+    pub normal: Vec3,
+    // Total area of the surface, the probability density of a uniformly chosen point is its inverse
+    pub area: f32,
+}
+
+pub trait Hittable: Send + Sync {
     fn hit(&self, ray: &Ray, t_min: f32, t_max: f32, hit_record: &mut HitRecord) -> bool;
+
+    /// World space bounds of the object, `None` for unbounded objects which can't be put into a BVH
+    fn bounding_box(&self) -> Option<Aabb>;
+
+    /// Only answers whether anything is hit in the given interval, used for shadow rays
+    fn shadow_check(&self, ray: &Ray, t_min: f32, t_max: f32) -> bool {
+        let mut temp_hit_record = HitRecord::new();
+        self.hit(ray, t_min, t_max, &mut temp_hit_record)
+    }
+
+    /// All intervals along the whole line of the ray (negative t included) which lie inside
+    /// of the object, sorted by t. Used for CSG, surfaces without an inside return none.
+    fn intervals(&self, _ray: &Ray) -> Vec<Interval> {
+        vec![]
+    }
+
+    /// Light emitted by the surface, only surfaces which can be sampled act as area lights
+    fn get_emission(&self) -> Option<Emission> {
+        None
+    }
+
+    /// Uniformly distributed random point on the surface, `None` if the surface can't be sampled
+    fn sample_surface(&self, _rng: &mut dyn RngCore) -> Option<SurfaceSample> {
+        None
+    }
 }
@@ -1,34 +1,52 @@
-use std::rc::Rc;
+use std::sync::Arc;
 
 use super::{
+    aabb::Aabb,
+    bvh::BvhNode,
     hittable::{HitRecord, Hittable},
     ray::Ray,
 };
 
 pub struct HittableList {
-    pub objects: Vec<Rc<dyn Hittable>>,
+    pub objects: Vec<Arc<dyn Hittable>>,
+    // Acceleration structures, only present after build_bvh() has been called
+    bvh: Option<Arc<dyn Hittable>>,
+    unbounded_objects: Vec<Arc<dyn Hittable>>,
 }
 
 impl HittableList {
     pub fn new() -> Self {
-        Self { objects: vec![] }
+        Self {
+            objects: vec![],
+            bvh: None,
+            unbounded_objects: vec![],
+        }
     }
 
-    pub fn add(&mut self, object: Rc<dyn Hittable>) {
+    pub fn add(&mut self, object: Arc<dyn Hittable>) {
         self.objects.push(object);
+        self.bvh = None;
+        self.unbounded_objects.clear();
     }
 
-    pub fn shadow_check(&self, ray: &Ray, t_min: f32, t_max: f32) -> bool {
-        let mut temp_hit_record = HitRecord::new();
-        let closest_so_far = t_max;
+    /// Puts all bounded objects into a BVH, unbounded ones are still tested linearly
+    pub fn build_bvh(&mut self) {
+        let (bounded_objects, unbounded_objects): (Vec<_>, Vec<_>) = self
+            .objects
+            .iter()
+            .cloned()
+            .partition(|object| object.bounding_box().is_some());
 
-        for object in &self.objects {
-            if object.hit(ray, t_min, closest_so_far, &mut temp_hit_record) {
-                return true;
-            }
-        }
+        self.bvh = if bounded_objects.is_empty() {
+            None
+        } else {
+            Some(BvhNode::build(bounded_objects))
+        };
+        self.unbounded_objects = unbounded_objects;
+    }
 
-        return false;
+    fn is_accelerated(&self) -> bool {
+        self.bvh.is_some() || !self.unbounded_objects.is_empty()
     }
 }
 
@@ -38,7 +56,21 @@ impl Hittable for HittableList {
         let mut closest_so_far = t_max;
         let mut hit_anything = false;
 
-        for object in &self.objects {
+        let objects = if self.is_accelerated() {
+            &self.unbounded_objects
+        } else {
+            &self.objects
+        };
+
+        if let Some(bvh) = &self.bvh {
+            if bvh.hit(ray, t_min, closest_so_far, &mut temp_hit_record) {
+                hit_anything = true;
+                closest_so_far = temp_hit_record.t;
+                *hit_record = temp_hit_record.clone();
+            }
+        }
+
+        for object in objects {
             if object.hit(ray, t_min, closest_so_far, &mut temp_hit_record) {
                 hit_anything = true;
                 closest_so_far = temp_hit_record.t;
@@ -48,4 +80,36 @@ impl Hittable for HittableList {
 
         hit_anything
     }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        let mut bounding_box: Option<Aabb> = None;
+
+        for object in &self.objects {
+            let object_box = object.bounding_box()?;
+            bounding_box = Some(match bounding_box {
+                Some(accumulated) => Aabb::surrounding_box(&accumulated, &object_box),
+                None => object_box,
+            });
+        }
+
+        bounding_box
+    }
+
+    fn shadow_check(&self, ray: &Ray, t_min: f32, t_max: f32) -> bool {
+        if let Some(bvh) = &self.bvh {
+            if bvh.shadow_check(ray, t_min, t_max) {
+                return true;
+            }
+        }
+
+        let objects = if self.is_accelerated() {
+            &self.unbounded_objects
+        } else {
+            &self.objects
+        };
+
+        objects
+            .iter()
+            .any(|object| object.shadow_check(ray, t_min, t_max))
+    }
 }
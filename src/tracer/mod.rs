@@ -1,3 +1,6 @@
+pub mod aabb;
+pub mod bvh;
 pub mod hittable;
 pub mod hittable_list;
 pub mod ray;
+pub mod triangle_bvh;
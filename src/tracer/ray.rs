@@ -3,17 +3,45 @@ use crate::utils::vec3::{Point, Vec3};
 pub struct Ray {
     pub origin: Point,
     pub direction: Vec3,
+    // Only camera rays carry differentials, secondary rays have none
+    pub differentials: Option<RayDifferentials>,
+}
+
+/// Rays through the neighbouring pixels to the right and above, they estimate how large
+/// the area covered by a pixel is on the hit surface
+#[derive(Clone, Copy)]
+pub struct RayDifferentials {
+    pub x_origin: Point,
+    pub x_direction: Vec3,
+    pub y_origin: Point,
+    pub y_direction: Vec3,
 }
 
 impl Ray {
     pub fn from_values(origin: &Point, direction: &Vec3) -> Self {
         Self {
-            origin: origin.clone(),
-            direction: direction.clone(),
+            origin: *origin,
+            direction: *direction,
+            differentials: None,
         }
     }
 
+    pub fn with_differentials(mut self, differentials: Option<RayDifferentials>) -> Self {
+        self.differentials = differentials;
+        self
+    }
+
     pub fn at(&self, t: f32) -> Point {
-        return &self.origin + &(&t * &self.direction);
+        &self.origin + &(&t * &self.direction)
+    }
+}
+
+impl RayDifferentials {
+    pub fn x_ray(&self) -> Ray {
+        Ray::from_values(&self.x_origin, &self.x_direction)
+    }
+
+    pub fn y_ray(&self) -> Ray {
+        Ray::from_values(&self.y_origin, &self.y_direction)
     }
 }
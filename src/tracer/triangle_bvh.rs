@@ -0,0 +1,296 @@
+use crate::utils::vec3::{Point, Vec3};
+
+use super::{aabb::Aabb, ray::Ray};
+
+const BIN_COUNT: usize = 16;
+// Triangle bounds are padded, otherwise rounding errors cull hits right at the edges
+const TRIANGLE_BOX_PADDING: f32 = 0.0001;
+const MAX_TRIANGLES_PER_LEAF: usize = 4;
+// Relative costs used by the surface area heuristic
+const TRAVERSAL_COST: f32 = 1.0;
+const INTERSECTION_COST: f32 = 1.0;
+
+// Flattened node, the left child of an inner node is always stored right after its parent
+#[derive(Clone, Copy)]
+struct TriangleBvhNode {
+    bounding_box: Aabb,
+    // Leaf: index of the first triangle in triangle_indices, inner node: index of the right child
+    offset: usize,
+    // Number of triangles of a leaf, zero for inner nodes
+    triangle_count: usize,
+    split_axis: usize,
+}
+
+impl TriangleBvhNode {
+    fn is_leaf(&self) -> bool {
+        self.triangle_count > 0
+    }
+}
+
+/// Bounding volume hierarchy over the triangles of a single mesh in object space,
+/// built with the binned surface area heuristic
+#[derive(Default)]
+pub struct TriangleBvh {
+    nodes: Vec<TriangleBvhNode>,
+    // Triangle indices (index of the triangle in the mesh index array divided by three) ordered by leaf
+    triangle_indices: Vec<usize>,
+}
+
+struct BuildTriangle {
+    bounding_box: Aabb,
+    centroid: Point,
+}
+
+#[derive(Clone, Copy)]
+struct Bin {
+    bounding_box: Option<Aabb>,
+    triangle_count: usize,
+}
+
+fn grow(bounding_box: Option<Aabb>, other: &Aabb) -> Option<Aabb> {
+    Some(match bounding_box {
+        Some(bounding_box) => Aabb::surrounding_box(&bounding_box, other),
+        None => *other,
+    })
+}
+
+fn surface_area(bounding_box: &Option<Aabb>) -> f32 {
+    match bounding_box {
+        Some(bounding_box) => {
+            let extent = bounding_box.extent();
+            2.0 * (extent.x() * extent.y() + extent.y() * extent.z() + extent.z() * extent.x())
+        }
+        None => 0.0,
+    }
+}
+
+impl TriangleBvh {
+    pub fn build(vertices: &[Vec3], indices: &[usize]) -> Self {
+        let build_triangles: Vec<BuildTriangle> = indices
+            .chunks_exact(3)
+            .map(|chunk| {
+                let tight_box = Aabb::from_points(&[
+                    vertices[chunk[0]],
+                    vertices[chunk[1]],
+                    vertices[chunk[2]],
+                ]);
+                let padding = Vec3::from_values(
+                    TRIANGLE_BOX_PADDING,
+                    TRIANGLE_BOX_PADDING,
+                    TRIANGLE_BOX_PADDING,
+                );
+                let bounding_box =
+                    Aabb::from_values(&tight_box.minimum - &padding, &tight_box.maximum + &padding);
+                BuildTriangle {
+                    bounding_box,
+                    centroid: bounding_box.centroid(),
+                }
+            })
+            .collect();
+
+        let mut bvh = TriangleBvh {
+            nodes: Vec::with_capacity(2 * build_triangles.len()),
+            triangle_indices: (0..build_triangles.len()).collect(),
+        };
+
+        if !build_triangles.is_empty() {
+            bvh.build_recursive(&build_triangles, 0, build_triangles.len());
+        }
+
+        bvh
+    }
+
+    fn build_recursive(&mut self, triangles: &[BuildTriangle], start: usize, end: usize) -> usize {
+        let node_index = self.nodes.len();
+        let triangle_range = &self.triangle_indices[start..end];
+
+        let mut bounding_box = triangles[triangle_range[0]].bounding_box;
+        let mut centroid_bounds = Aabb::from_points(&[triangles[triangle_range[0]].centroid]);
+        for &triangle_index in triangle_range {
+            bounding_box =
+                Aabb::surrounding_box(&bounding_box, &triangles[triangle_index].bounding_box);
+            centroid_bounds = Aabb::surrounding_box(
+                &centroid_bounds,
+                &Aabb::from_points(&[triangles[triangle_index].centroid]),
+            );
+        }
+
+        self.nodes.push(TriangleBvhNode {
+            bounding_box,
+            offset: start,
+            triangle_count: end - start,
+            split_axis: 0,
+        });
+
+        let triangle_count = end - start;
+        if triangle_count <= MAX_TRIANGLES_PER_LEAF {
+            return node_index;
+        }
+
+        let axis = centroid_bounds.longest_axis();
+        let axis_minimum = centroid_bounds.minimum[axis];
+        let axis_extent = centroid_bounds.maximum[axis] - axis_minimum;
+
+        // All centroids coincide, splitting won't help
+        if axis_extent <= 0.0 {
+            return node_index;
+        }
+
+        let bin_of = |centroid: &Point| -> usize {
+            (((centroid[axis] - axis_minimum) / axis_extent * BIN_COUNT as f32) as usize)
+                .min(BIN_COUNT - 1)
+        };
+
+        let mut bins = [Bin {
+            bounding_box: None,
+            triangle_count: 0,
+        }; BIN_COUNT];
+
+        for &triangle_index in triangle_range {
+            let bin = &mut bins[bin_of(&triangles[triangle_index].centroid)];
+            bin.bounding_box = grow(bin.bounding_box, &triangles[triangle_index].bounding_box);
+            bin.triangle_count += 1;
+        }
+
+        // Sweep from the right to get the cost of every right hand side, then from the left
+        let mut right_areas = [0.0; BIN_COUNT];
+        let mut right_counts = [0; BIN_COUNT];
+        let mut accumulated_box = None;
+        let mut accumulated_count = 0;
+        for bin_index in (1..BIN_COUNT).rev() {
+            if let Some(bin_box) = &bins[bin_index].bounding_box {
+                accumulated_box = grow(accumulated_box, bin_box);
+            }
+            accumulated_count += bins[bin_index].triangle_count;
+            right_areas[bin_index] = surface_area(&accumulated_box);
+            right_counts[bin_index] = accumulated_count;
+        }
+
+        let mut best_split = 0;
+        let mut best_cost = f32::INFINITY;
+        accumulated_box = None;
+        accumulated_count = 0;
+        for bin_index in 0..BIN_COUNT - 1 {
+            if let Some(bin_box) = &bins[bin_index].bounding_box {
+                accumulated_box = grow(accumulated_box, bin_box);
+            }
+            accumulated_count += bins[bin_index].triangle_count;
+
+            let cost = accumulated_count as f32 * surface_area(&accumulated_box)
+                + right_counts[bin_index + 1] as f32 * right_areas[bin_index + 1];
+            if cost < best_cost {
+                best_cost = cost;
+                best_split = bin_index;
+            }
+        }
+
+        let parent_area = surface_area(&Some(bounding_box));
+        let split_cost = TRAVERSAL_COST + INTERSECTION_COST * best_cost / parent_area;
+        let leaf_cost = INTERSECTION_COST * triangle_count as f32;
+        if split_cost >= leaf_cost && parent_area > 0.0 {
+            return node_index;
+        }
+
+        // Partition the triangle indices of this node in place
+        let range = &mut self.triangle_indices[start..end];
+        let mut middle = 0;
+        for i in 0..range.len() {
+            if bin_of(&triangles[range[i]].centroid) <= best_split {
+                range.swap(i, middle);
+                middle += 1;
+            }
+        }
+        let middle = start + middle;
+
+        if middle == start || middle == end {
+            return node_index;
+        }
+
+        self.build_recursive(triangles, start, middle);
+        let right_child = self.build_recursive(triangles, middle, end);
+
+        self.nodes[node_index].offset = right_child;
+        self.nodes[node_index].triangle_count = 0;
+        self.nodes[node_index].split_axis = axis;
+
+        node_index
+    }
+
+    pub fn bounding_box(&self) -> Option<Aabb> {
+        self.nodes.first().map(|node| node.bounding_box)
+    }
+
+    /// Finds the closest triangle, `intersect_triangle` gets the triangle index and the
+    /// current interval and returns the distance of a hit within that interval.
+    pub fn hit<F>(&self, ray: &Ray, t_min: f32, t_max: f32, mut intersect_triangle: F) -> bool
+    where
+        F: FnMut(usize, f32, f32) -> Option<f32>,
+    {
+        let mut closest_so_far = t_max;
+        let mut hit_anything = false;
+
+        self.traverse(
+            ray,
+            t_min,
+            &mut closest_so_far,
+            |triangle_index, closest_so_far| {
+                if let Some(t) = intersect_triangle(triangle_index, t_min, *closest_so_far) {
+                    *closest_so_far = t;
+                    hit_anything = true;
+                }
+                false
+            },
+        );
+
+        hit_anything
+    }
+
+    /// Stops at the first triangle for which `intersect_triangle` returns true
+    pub fn any_hit<F>(&self, ray: &Ray, t_min: f32, t_max: f32, mut intersect_triangle: F) -> bool
+    where
+        F: FnMut(usize, f32, f32) -> bool,
+    {
+        let mut t_max = t_max;
+        self.traverse(ray, t_min, &mut t_max, |triangle_index, t_max| {
+            intersect_triangle(triangle_index, t_min, *t_max)
+        })
+    }
+
+    // Front to back traversal with an explicit stack, returns true if `visit_triangle` requested to stop
+    fn traverse<F>(&self, ray: &Ray, t_min: f32, t_max: &mut f32, mut visit_triangle: F) -> bool
+    where
+        F: FnMut(usize, &mut f32) -> bool,
+    {
+        if self.nodes.is_empty() {
+            return false;
+        }
+
+        let mut stack = Vec::with_capacity(64);
+        stack.push(0);
+
+        while let Some(node_index) = stack.pop() {
+            let node: &TriangleBvhNode = &self.nodes[node_index];
+            if !node.bounding_box.hit(ray, t_min, *t_max) {
+                continue;
+            }
+
+            if node.is_leaf() {
+                for &triangle_index in
+                    &self.triangle_indices[node.offset..node.offset + node.triangle_count]
+                {
+                    if visit_triangle(triangle_index, t_max) {
+                        return true;
+                    }
+                }
+            } else if ray.direction[node.split_axis] < 0.0 {
+                stack.push(node_index + 1);
+                stack.push(node.offset);
+            } else {
+                stack.push(node.offset);
+                stack.push(node_index + 1);
+            }
+        }
+
+        false
+    }
+}
@@ -0,0 +1,146 @@
+use crate::{
+    scene::{light::get_surface_color, scene::Scene},
+    tracer::hittable::HitRecord,
+};
+
+use super::vec3::{Color, Vec3};
+
+/// Arbitrary output variable, written as an additional image next to the rendered image.
+/// The values are captured from the hit of the ray through the center of each pixel.
+#[derive(Clone, Copy)]
+pub enum Aov {
+    // Distance along the viewing direction of the camera
+    Depth,
+    // World space normal
+    Normal,
+    // Surface color without any lighting
+    Albedo,
+    TextureCoordinate,
+    // Index of the surface in the scene file
+    ObjectIndex,
+    // Index of the material in the scene file, counting the materials of all surfaces
+    MaterialIndex,
+}
+
+impl Aov {
+    pub const ALL: [Aov; 6] = [
+        Aov::Depth,
+        Aov::Normal,
+        Aov::Albedo,
+        Aov::TextureCoordinate,
+        Aov::ObjectIndex,
+        Aov::MaterialIndex,
+    ];
+
+    pub fn parse(name: &str) -> Option<Aov> {
+        Aov::ALL.into_iter().find(|aov| aov.name() == name)
+    }
+
+    /// Used in the command line and as suffix of the file name
+    pub fn name(&self) -> &'static str {
+        match self {
+            Aov::Depth => "depth",
+            Aov::Normal => "normal",
+            Aov::Albedo => "albedo",
+            Aov::TextureCoordinate => "uv",
+            Aov::ObjectIndex => "object",
+            Aov::MaterialIndex => "material",
+        }
+    }
+
+    /// Only the albedo is a color, the other variables store data
+    pub fn is_color(&self) -> bool {
+        matches!(self, Aov::Albedo)
+    }
+
+    /// Raw value of the variable, indices are stored in all three channels.
+    /// Rays which don't hit anything give zero.
+    pub fn value(&self, hit_record: Option<&HitRecord>, scene: &Scene) -> Color {
+        let Some(hit_record) = hit_record else {
+            return Color::new();
+        };
+
+        match self {
+            Aov::Depth => {
+                let viewing_direction =
+                    (&scene.camera.lookat - &scene.camera.position).unit_vector();
+                let depth = (&hit_record.point - &scene.camera.position).dot(&viewing_direction);
+                Color::from_values(depth, depth, depth)
+            }
+            Aov::Normal => hit_record.normal.unit_vector(),
+            Aov::Albedo => get_surface_color(hit_record),
+            Aov::TextureCoordinate => match hit_record.texture_coordinate {
+                Some(texture_coordinate) => {
+                    Vec3::from_values(texture_coordinate.x(), texture_coordinate.y(), 0.0)
+                }
+                None => Color::new(),
+            },
+            Aov::ObjectIndex => index_value(hit_record.object_index),
+            Aov::MaterialIndex => index_value(hit_record.material_index),
+        }
+    }
+
+    /// Maps the raw values of a whole image into the displayable range
+    pub fn display_colors(&self, values: &[Color]) -> Vec<Color> {
+        match self {
+            // Normalized by the farthest hit, misses stay black
+            Aov::Depth => {
+                let max_depth = values.iter().fold(0.0f32, |max, value| max.max(value.r()));
+                values
+                    .iter()
+                    .map(|value| {
+                        if max_depth > 0.0 {
+                            value / &max_depth
+                        } else {
+                            Color::new()
+                        }
+                    })
+                    .collect()
+            }
+            Aov::Normal => values
+                .iter()
+                .map(|normal| {
+                    if normal.length() > 0.0 {
+                        &(normal * &0.5) + &Color::from_values(0.5, 0.5, 0.5)
+                    } else {
+                        Color::new()
+                    }
+                })
+                .collect(),
+            Aov::Albedo => values.to_vec(),
+            // Repeating texture coordinates are wrapped into the unit square
+            Aov::TextureCoordinate => values
+                .iter()
+                .map(|texture_coordinate| {
+                    Color::from_values(
+                        texture_coordinate.x().rem_euclid(1.0),
+                        texture_coordinate.y().rem_euclid(1.0),
+                        0.0,
+                    )
+                })
+                .collect(),
+            Aov::ObjectIndex | Aov::MaterialIndex => {
+                values.iter().map(|value| index_color(value.r())).collect()
+            }
+        }
+    }
+}
+
+// Indices are shifted by one, so zero marks pixels without a surface
+fn index_value(index: Option<usize>) -> Color {
+    let value = index.map_or(0.0, |index| (index + 1) as f32);
+    Color::from_values(value, value, value)
+}
+
+// Distinct color for every index, easy to select in a compositing program
+fn index_color(value: f32) -> Color {
+    if value <= 0.0 {
+        return Color::new();
+    }
+    let hash = (value as u32).wrapping_mul(0x9E37_79B9).rotate_left(16);
+    Color::from_values(
+        0.2 + 0.8 * (hash & 0xFF) as f32 / 255.0,
+        0.2 + 0.8 * ((hash >> 8) & 0xFF) as f32 / 255.0,
+        0.2 + 0.8 * ((hash >> 16) & 0xFF) as f32 / 255.0,
+    )
+}
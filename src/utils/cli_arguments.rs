@@ -0,0 +1,88 @@
+use std::env;
+
+use super::{aov::Aov, hdr_creator::OutputFormat, tone_mapping::ToneMapOperator};
+
+#[derive(Clone, Copy)]
+pub enum Integrator {
+    // Direct lighting with mirror reflection and refraction
+    Whitted,
+    // Global illumination, needs many samples per pixel to converge
+    Path,
+    // White surfaces only shaded by ambient occlusion
+    AmbientOcclusion,
+}
+
+pub struct CliArguments {
+    pub sample_size: Option<usize>,
+    pub thread_count: usize,
+    pub seed: Option<u64>,
+    pub integrator: Integrator,
+    pub aovs: Vec<Aov>,
+    // Overrides the format given by the extension of the output file
+    pub output_format: Option<OutputFormat>,
+    // Override the tone mapping of the scene file
+    pub tone_map_operator: Option<ToneMapOperator>,
+    pub exposure: Option<f32>,
+}
+
+impl CliArguments {
+    /// Parses the optional flags, the first argument is always the path to the XML-File
+    pub fn parse() -> Self {
+        let mut arguments = CliArguments {
+            sample_size: None,
+            thread_count: std::thread::available_parallelism()
+                .map(|count| count.get())
+                .unwrap_or(1),
+            seed: None,
+            integrator: Integrator::Whitted,
+            aovs: vec![],
+            output_format: None,
+            tone_map_operator: None,
+            exposure: None,
+        };
+
+        for arg in env::args().skip(2) {
+            if let Some(value) = arg.strip_prefix("-s=") {
+                if let Ok(size) = value.parse::<usize>() {
+                    arguments.sample_size = Some(size);
+                }
+            } else if let Some(value) = arg.strip_prefix("--threads=") {
+                if let Ok(count) = value.parse::<usize>() {
+                    arguments.thread_count = count.max(1);
+                }
+            } else if let Some(value) = arg.strip_prefix("--seed=") {
+                if let Ok(seed) = value.parse::<u64>() {
+                    arguments.seed = Some(seed);
+                }
+            } else if let Some(value) = arg.strip_prefix("--integrator=") {
+                match value {
+                    "whitted" => arguments.integrator = Integrator::Whitted,
+                    "path" => arguments.integrator = Integrator::Path,
+                    "ao" => arguments.integrator = Integrator::AmbientOcclusion,
+                    _ => {}
+                }
+            } else if let Some(value) = arg.strip_prefix("--aovs=") {
+                // Comma separated names, `all` selects every AOV
+                arguments.aovs = if value == "all" {
+                    Aov::ALL.to_vec()
+                } else {
+                    value.split(',').filter_map(Aov::parse).collect()
+                };
+            } else if let Some(value) = arg.strip_prefix("--format=") {
+                if let Some(format) = OutputFormat::parse(value) {
+                    arguments.output_format = Some(format);
+                }
+            } else if let Some(value) = arg.strip_prefix("--tonemap=") {
+                if let Some(operator) = ToneMapOperator::parse(value) {
+                    arguments.tone_map_operator = Some(operator);
+                }
+            } else if let Some(value) = arg.strip_prefix("--exposure=") {
+                if let Ok(exposure) = value.parse::<f32>() {
+                    arguments.exposure = Some(exposure);
+                }
+            }
+        }
+
+        arguments
+    }
+}
@@ -1,13 +1,84 @@
 use super::vec3::Color;
 
+/// Quantizes a linear color to 8 bit sRGB
 pub fn to_png_color(color: &Color, pixel_colors: &mut Vec<u8>, sample_size: f32) {
 
     let scaling_factor = 1.0 / sample_size;
 
     let temp_values: [u8; 3] = [
-        ((color.r() * scaling_factor).clamp(0.0, 0.999) * 255.999) as u8,
-        ((color.g() * scaling_factor).clamp(0.0, 0.999) * 255.999) as u8,
-        ((color.b() * scaling_factor).clamp(0.0, 0.999) * 255.999) as u8,
+        (linear_to_srgb((color.r() * scaling_factor).clamp(0.0, 0.999)) * 255.999) as u8,
+        (linear_to_srgb((color.g() * scaling_factor).clamp(0.0, 0.999)) * 255.999) as u8,
+        (linear_to_srgb((color.b() * scaling_factor).clamp(0.0, 0.999)) * 255.999) as u8,
     ];
     pixel_colors.extend(&temp_values);
 }
+
+/// Quantizes values which aren't colors (e.g. normals) to 8 bit without encoding them
+pub fn to_png_data(color: &Color, pixel_colors: &mut Vec<u8>) {
+    let temp_values: [u8; 3] = [
+        (color.r().clamp(0.0, 0.999) * 255.999) as u8,
+        (color.g().clamp(0.0, 0.999) * 255.999) as u8,
+        (color.b().clamp(0.0, 0.999) * 255.999) as u8,
+    ];
+    pixel_colors.extend(&temp_values);
+}
+
+/// Decodes a channel in [0, 1] from the sRGB transfer function to linear light
+pub fn srgb_to_linear(value: f32) -> f32 {
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+pub fn linear_to_srgb(value: f32) -> f32 {
+    if value <= 0.0031308 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn srgb_round_trip() {
+        for step in 0..=255 {
+            let value = step as f32 / 255.0;
+            assert!((linear_to_srgb(srgb_to_linear(value)) - value).abs() < 1e-5);
+            assert!((srgb_to_linear(linear_to_srgb(value)) - value).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn srgb_reference_values() {
+        assert_eq!(srgb_to_linear(0.0), 0.0);
+        assert!((srgb_to_linear(1.0) - 1.0).abs() < 1e-6);
+        assert!((srgb_to_linear(0.5) - 0.214_041).abs() < 1e-5);
+        assert!((linear_to_srgb(0.18) - 0.461_356).abs() < 1e-5);
+    }
+
+    #[test]
+    fn srgb_curves_are_continuous_at_the_linear_segment() {
+        let below = srgb_to_linear(0.04045);
+        let above = srgb_to_linear(0.04045 + 1e-6);
+        assert!((above - below).abs() < 1e-5);
+
+        let below = linear_to_srgb(0.0031308);
+        let above = linear_to_srgb(0.0031308 + 1e-7);
+        assert!((above - below).abs() < 1e-5);
+    }
+
+    #[test]
+    fn png_colors_are_encoded_and_clamped() {
+        let mut pixel_colors = vec![];
+        to_png_color(&Color::from_values(0.5, 4.0, -1.0), &mut pixel_colors, 2.0);
+        to_png_data(&Color::from_values(0.5, 4.0, -1.0), &mut pixel_colors);
+
+        // Linear 0.25 is 0.537 in sRGB, values which aren't colors stay linear
+        assert_eq!(pixel_colors, vec![137, 255, 0, 127, 255, 0]);
+    }
+}
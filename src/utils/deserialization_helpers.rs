@@ -1,6 +1,9 @@
-use serde::Deserialize;
+use serde::{de::Error, Deserialize};
 
-use crate::utils::vec3::{Color, Point, Vec3};
+use crate::utils::{
+    mat4::Mat4,
+    vec3::{Color, Point, Vec3},
+};
 
 pub fn deserialize_color<'de, D>(deserializer: D) -> Result<Color, D::Error>
 where
@@ -20,6 +23,13 @@ where
     Ok(Color::from_values(color.r, color.g, color.b))
 }
 
+pub fn deserialize_optional_color<'de, D>(deserializer: D) -> Result<Option<Color>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    deserialize_color(deserializer).map(Some)
+}
+
 pub fn deserialize_point<'de, D>(deserializer: D) -> Result<Color, D::Error>
 where
     D: serde::Deserializer<'de>,
@@ -55,3 +65,30 @@ where
     let vector = VectorDeserialized::deserialize(deserializer)?;
     Ok(Vec3::from_values(vector.x, vector.y, vector.z))
 }
+
+/// Reads the 16 whitespace separated values of a 4x4 matrix in row-major order
+pub fn deserialize_matrix<'de, D>(deserializer: D) -> Result<Mat4, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    pub struct MatrixDeserialized {
+        #[serde(rename = "@values")]
+        pub values: String,
+    }
+
+    let matrix = MatrixDeserialized::deserialize(deserializer)?;
+    let values = matrix
+        .values
+        .split_whitespace()
+        .map(|value| value.parse::<f32>().map_err(D::Error::custom))
+        .collect::<Result<Vec<f32>, D::Error>>()?;
+
+    let matrix: [f32; 16] = values.try_into().map_err(|values: Vec<f32>| {
+        D::Error::custom(format!(
+            "Matrix requires 16 values, {} were given",
+            values.len()
+        ))
+    })?;
+    Ok(Mat4 { matrix })
+}
@@ -1,19 +1,32 @@
 use std::fs::{self, File};
+use std::path::{Path, PathBuf};
 use std::{env, io::BufReader};
 
-use png::Decoder;
+use png::{BitDepth, ColorType, Decoder, Transformations};
 
-use crate::scene::material::Texture;
+use crate::scene::material::{ColorSpace, Texture};
 use crate::scene::scene::Scene;
 
-use super::vec3::Color;
+use super::{color_utility::srgb_to_linear, vec3::Color};
 
-pub fn load_and_deserialize_scene() -> Scene {
+/// Errors name the scene file, e.g. a texture which couldn't be loaded
+pub fn load_and_deserialize_scene() -> Result<Scene, String> {
     let path_to_xml_file = env::args().nth(1).unwrap();
     let file =
-        File::open(path_to_xml_file).expect("Failed to open the file at the specified file path");
+        File::open(&path_to_xml_file).expect("Failed to open the file at the specified file path");
     let reader = BufReader::new(file);
-    quick_xml::de::from_reader(reader).expect("Deserialization failed!")
+    let mut scene: Scene = quick_xml::de::from_reader(reader)
+        .map_err(|err| format!("Failed to load scene {}: {}", path_to_xml_file, err))?;
+    scene.lights.add_emissive_surfaces(&scene.surfaces);
+    if let Some(environment) = &scene.environment {
+        scene.lights.add_environment_light(environment.clone());
+    }
+    Ok(scene)
+}
+
+/// Location of a rendered image, all of them are written into `output_files`
+pub fn output_path(file_name: &str) -> PathBuf {
+    Path::new("output_files").join(file_name)
 }
 
 pub fn load_obj_file(name: &String) -> Result<String, std::io::Error> {
@@ -28,28 +41,400 @@ pub fn load_obj_file(name: &String) -> Result<String, std::io::Error> {
     }
 }
 
-pub fn load_texture_file(name: &String) -> Texture {
+/// Loads a PNG texture of any color type and bit depth, the pixels are converted to linear
+/// values for shading. Gray images are expanded to RGB and missing alpha is opaque.
+/// The settings are taken from the deserialized texture, mipmaps are only built for the
+/// filters which use them.
+pub fn load_texture_file(texture: &Texture) -> Result<Texture, String> {
+    let name = &texture.name;
     let path_to_texture_file = format!("./scenes/{}", name);
+    let texture_error = |err: String| format!("Failed to load texture {}: {}", name, err);
 
-    let file = File::open(path_to_texture_file).expect("Failed to open image file");
-    let decoder = Decoder::new(file);
-    let mut reader = decoder.read_info().unwrap();
+    let file = File::open(path_to_texture_file).map_err(|err| texture_error(err.to_string()))?;
+    let mut decoder = Decoder::new(file);
+    // Palettes and bit depths below 8 are expanded, transparency chunks become an alpha channel
+    decoder.set_transformations(Transformations::EXPAND);
+    let mut reader = decoder
+        .read_info()
+        .map_err(|err| texture_error(err.to_string()))?;
     let mut buf = vec![0; reader.output_buffer_size()];
-    let info = reader.next_frame(&mut buf).unwrap();
+    let info = reader
+        .next_frame(&mut buf)
+        .map_err(|err| texture_error(err.to_string()))?;
+    buf.truncate(info.buffer_size());
+
+    let (color_channels, has_alpha) = match info.color_type {
+        ColorType::Grayscale => (1, false),
+        ColorType::GrayscaleAlpha => (1, true),
+        ColorType::Rgb => (3, false),
+        ColorType::Rgba => (3, true),
+        ColorType::Indexed => return Err(texture_error("palette was not expanded".to_string())),
+    };
+
+    // 16 bit samples are stored big endian
+    let samples: Vec<f32> = match info.bit_depth {
+        BitDepth::Sixteen => buf
+            .chunks_exact(2)
+            .map(|sample| u16::from_be_bytes([sample[0], sample[1]]) as f32 / 65535.0)
+            .collect(),
+        _ => buf.iter().map(|&sample| sample as f32 / 255.0).collect(),
+    };
+
+    let decode = |value: f32| match texture.colorspace {
+        ColorSpace::Srgb => srgb_to_linear(value),
+        ColorSpace::Linear => value,
+    };
 
     let mut texture_pixels = Vec::new();
+    let mut texture_alpha = Vec::new();
 
-    for pixel in buf.chunks_exact_mut(3) {
-        texture_pixels.push(Color::from_values(
-            pixel[0] as f32 / 255.0,
-            pixel[1] as f32 / 255.0,
-            pixel[2] as f32 / 255.0,
-        ));
+    for pixel in samples.chunks_exact(info.color_type.samples()) {
+        texture_pixels.push(if color_channels == 1 {
+            let gray = decode(pixel[0]);
+            Color::from_values(gray, gray, gray)
+        } else {
+            Color::from_values(decode(pixel[0]), decode(pixel[1]), decode(pixel[2]))
+        });
+        // Alpha is always linear
+        texture_alpha.push(if has_alpha {
+            pixel[color_channels]
+        } else {
+            1.0
+        });
     }
-    Texture{
-        name: name.to_string(),
+    let mut loaded_texture = Texture {
         width: info.width as f32,
         height: info.height as f32,
-        texture_pixels
+        texture_pixels,
+        texture_alpha,
+        mip_levels: vec![],
+        ..texture.clone()
+    };
+    if loaded_texture.filter.uses_mipmaps() {
+        loaded_texture.build_mipmaps();
+    }
+    Ok(loaded_texture)
+}
+
+/// Floating point image, rows are stored from top to bottom
+pub struct HdrImage {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<Color>,
+}
+
+/// Loads a Radiance (`.hdr`) or PFM (`.pfm`) image, the format is chosen by the extension
+pub fn load_hdr_image(name: &String) -> Result<HdrImage, String> {
+    let path_to_image_file = format!("./scenes/{}", name);
+    let bytes = fs::read(&path_to_image_file)
+        .map_err(|err| format!("Failed to read image file {}: {}", path_to_image_file, err))?;
+
+    let extension = Path::new(name)
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| extension.to_ascii_lowercase());
+    let image = match extension.as_deref() {
+        Some("hdr") => decode_radiance_hdr(&bytes),
+        Some("pfm") => decode_pfm(&bytes),
+        _ => Err("Only .hdr and .pfm images are supported".to_string()),
+    }
+    .map_err(|err| format!("{}: {}", name, err))?;
+
+    if image.width == 0 || image.height == 0 {
+        return Err(format!("{}: image is empty", name));
+    }
+    Ok(image)
+}
+
+// Reads up to the next newline and advances the position behind it
+fn read_line<'a>(bytes: &'a [u8], position: &mut usize) -> Result<&'a str, String> {
+    let rest = &bytes[*position..];
+    let length = rest
+        .iter()
+        .position(|&byte| byte == b'\n')
+        .ok_or("unexpected end of header")?;
+    *position += length + 1;
+    std::str::from_utf8(&rest[..length]).map_err(|_| "header is not valid text".to_string())
+}
+
+fn decode_radiance_hdr(bytes: &[u8]) -> Result<HdrImage, String> {
+    let mut position = 0;
+    let magic = read_line(bytes, &mut position)?;
+    if !magic.starts_with("#?") {
+        return Err("missing Radiance header".to_string());
+    }
+    loop {
+        let line = read_line(bytes, &mut position)?;
+        if line.is_empty() {
+            break;
+        }
+        if let Some(format) = line.strip_prefix("FORMAT=") {
+            if format != "32-bit_rle_rgbe" {
+                return Err(format!("unsupported format {}", format));
+            }
+        }
+    }
+
+    // Only the standard orientation, rows from top to bottom and columns from left to right
+    let resolution: Vec<&str> = read_line(bytes, &mut position)?
+        .split_whitespace()
+        .collect();
+    let (height, width) = match resolution[..] {
+        ["-Y", height, "+X", width] => (
+            height.parse::<usize>().map_err(|_| "invalid height")?,
+            width.parse::<usize>().map_err(|_| "invalid width")?,
+        ),
+        _ => return Err("unsupported resolution line".to_string()),
+    };
+
+    let mut next_byte = || -> Result<u8, String> {
+        let byte = *bytes.get(position).ok_or("unexpected end of pixel data")?;
+        position += 1;
+        Ok(byte)
+    };
+
+    let mut pixels = Vec::with_capacity(width * height);
+    let mut scanline = vec![[0u8; 4]; width];
+    for _ in 0..height {
+        let first = [next_byte()?, next_byte()?, next_byte()?, next_byte()?];
+        let is_run_length_encoded = first[0] == 2
+            && first[1] == 2
+            && (8..32768).contains(&width)
+            && ((first[2] as usize) << 8 | first[3] as usize) == width;
+
+        if is_run_length_encoded {
+            // Each of the four components is encoded separately
+            for component in 0..4 {
+                let mut column = 0;
+                while column < width {
+                    let count = next_byte()? as usize;
+                    let (length, is_run) = if count > 128 {
+                        (count - 128, true)
+                    } else {
+                        (count, false)
+                    };
+                    if length == 0 || column + length > width {
+                        return Err("corrupt run length encoding".to_string());
+                    }
+                    let run_value = if is_run { next_byte()? } else { 0 };
+                    for pixel in &mut scanline[column..column + length] {
+                        pixel[component] = if is_run { run_value } else { next_byte()? };
+                    }
+                    column += length;
+                }
+            }
+        } else {
+            scanline[0] = first;
+            for pixel in scanline.iter_mut().skip(1) {
+                *pixel = [next_byte()?, next_byte()?, next_byte()?, next_byte()?];
+            }
+        }
+
+        pixels.extend(scanline.iter().map(|&[r, g, b, exponent]| {
+            if exponent == 0 {
+                Color::new()
+            } else {
+                let factor = 2f32.powi(exponent as i32 - 136);
+                Color::from_values(
+                    (r as f32 + 0.5) * factor,
+                    (g as f32 + 0.5) * factor,
+                    (b as f32 + 0.5) * factor,
+                )
+            }
+        }));
+    }
+
+    Ok(HdrImage {
+        width,
+        height,
+        pixels,
+    })
+}
+
+fn decode_pfm(bytes: &[u8]) -> Result<HdrImage, String> {
+    // The header consists of three whitespace separated tokens after the magic number,
+    // the raster starts after the single whitespace character following the scale
+    let mut position = 0;
+    let mut tokens = Vec::with_capacity(4);
+    while tokens.len() < 4 {
+        while bytes
+            .get(position)
+            .ok_or("unexpected end of header")?
+            .is_ascii_whitespace()
+        {
+            position += 1;
+        }
+        let start = position;
+        while bytes
+            .get(position)
+            .is_some_and(|byte| !byte.is_ascii_whitespace())
+        {
+            position += 1;
+        }
+        tokens.push(
+            std::str::from_utf8(&bytes[start..position])
+                .map_err(|_| "header is not valid text".to_string())?,
+        );
+    }
+    position += 1;
+
+    let channels = match tokens[0] {
+        "PF" => 3,
+        "Pf" => 1,
+        _ => return Err("missing PFM header".to_string()),
+    };
+    let width = tokens[1].parse::<usize>().map_err(|_| "invalid width")?;
+    let height = tokens[2].parse::<usize>().map_err(|_| "invalid height")?;
+    let scale = tokens[3].parse::<f32>().map_err(|_| "invalid scale")?;
+    if width == 0 || height == 0 {
+        return Err("image is empty".to_string());
+    }
+    // A negative scale marks little endian data
+    let little_endian = scale < 0.0;
+
+    let raster = bytes
+        .get(position..position + width * height * channels * 4)
+        .ok_or("unexpected end of pixel data")?;
+    let values: Vec<f32> = raster
+        .chunks_exact(4)
+        .map(|chunk| {
+            let chunk = [chunk[0], chunk[1], chunk[2], chunk[3]];
+            if little_endian {
+                f32::from_le_bytes(chunk)
+            } else {
+                f32::from_be_bytes(chunk)
+            }
+        })
+        .collect();
+
+    // PFM rows are stored from bottom to top
+    let mut pixels = Vec::with_capacity(width * height);
+    for row in values.chunks_exact(width * channels).rev() {
+        pixels.extend(row.chunks_exact(channels).map(|pixel| match pixel {
+            [r, g, b] => Color::from_values(*r, *g, *b),
+            _ => Color::from_values(pixel[0], pixel[0], pixel[0]),
+        }));
+    }
+
+    Ok(HdrImage {
+        width,
+        height,
+        pixels,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_color(color: &Color, r: f32, g: f32, b: f32) {
+        assert_eq!((color.r(), color.g(), color.b()), (r, g, b));
+    }
+
+    fn pfm(header: &str, values: &[f32], little_endian: bool) -> Vec<u8> {
+        let mut bytes = header.as_bytes().to_vec();
+        for value in values {
+            if little_endian {
+                bytes.extend(value.to_le_bytes());
+            } else {
+                bytes.extend(value.to_be_bytes());
+            }
+        }
+        bytes
+    }
+
+    #[test]
+    fn pfm_rows_are_flipped_to_top_to_bottom() {
+        let bytes = pfm(
+            "PF\n2 2\n-1.0\n",
+            &[
+                1.0, 0.0, 0.0, 0.0, 1.0, 0.0, // bottom row
+                0.0, 0.0, 1.0, 2.0, 3.0, 4.0, // top row
+            ],
+            true,
+        );
+        let image = decode_pfm(&bytes).unwrap();
+
+        assert_eq!((image.width, image.height), (2, 2));
+        assert_color(&image.pixels[0], 0.0, 0.0, 1.0);
+        assert_color(&image.pixels[1], 2.0, 3.0, 4.0);
+        assert_color(&image.pixels[2], 1.0, 0.0, 0.0);
+        assert_color(&image.pixels[3], 0.0, 1.0, 0.0);
+    }
+
+    #[test]
+    fn pfm_big_endian_grayscale() {
+        let bytes = pfm("Pf 2 1 1.0\n", &[0.25, 8.0], false);
+        let image = decode_pfm(&bytes).unwrap();
+
+        assert_color(&image.pixels[0], 0.25, 0.25, 0.25);
+        assert_color(&image.pixels[1], 8.0, 8.0, 8.0);
+    }
+
+    #[test]
+    fn pfm_errors() {
+        // One value short
+        let truncated = pfm("PF\n1 1\n-1.0\n", &[1.0, 1.0], true);
+        assert!(decode_pfm(&truncated).is_err());
+        assert!(decode_pfm(b"PF\n0 1\n-1.0\n").is_err());
+        assert!(decode_pfm(b"P6\n1 1\n255\n").is_err());
+        assert!(decode_pfm(b"PF\n1").is_err());
+    }
+
+    fn radiance_header(width: usize, height: usize) -> Vec<u8> {
+        format!(
+            "#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y {} +X {}\n",
+            height, width
+        )
+        .into_bytes()
+    }
+
+    #[test]
+    fn radiance_flat_scanlines() {
+        let mut bytes = radiance_header(2, 1);
+        bytes.extend([128, 64, 0, 129, 255, 255, 255, 0]);
+        let image = decode_radiance_hdr(&bytes).unwrap();
+
+        assert_eq!((image.width, image.height), (2, 1));
+        assert_color(&image.pixels[0], 128.5 / 128.0, 64.5 / 128.0, 0.5 / 128.0);
+        // An exponent of zero is black regardless of the mantissas
+        assert_color(&image.pixels[1], 0.0, 0.0, 0.0);
+    }
+
+    #[test]
+    fn radiance_run_length_encoded_scanlines() {
+        let mut bytes = radiance_header(8, 1);
+        bytes.extend([2, 2, 0, 8]);
+        // Runs for the mantissas and literal values for the exponents
+        bytes.extend([128 + 8, 128]);
+        bytes.extend([128 + 8, 64]);
+        bytes.extend([128 + 4, 0, 128 + 4, 255]);
+        bytes.extend([8, 129, 129, 129, 129, 129, 129, 129, 130]);
+        let image = decode_radiance_hdr(&bytes).unwrap();
+
+        assert_eq!(image.pixels.len(), 8);
+        assert_color(&image.pixels[0], 128.5 / 128.0, 64.5 / 128.0, 0.5 / 128.0);
+        assert_color(&image.pixels[6], 128.5 / 128.0, 64.5 / 128.0, 255.5 / 128.0);
+        assert_color(&image.pixels[7], 128.5 / 64.0, 64.5 / 64.0, 255.5 / 64.0);
+    }
+
+    #[test]
+    fn radiance_errors() {
+        let mut truncated = radiance_header(2, 2);
+        truncated.extend([128, 64, 0, 129]);
+        assert!(decode_radiance_hdr(&truncated).is_err());
+
+        // A run which is longer than the scanline
+        let mut overlong_run = radiance_header(8, 1);
+        overlong_run.extend([2, 2, 0, 8, 128 + 9, 1]);
+        assert!(decode_radiance_hdr(&overlong_run).is_err());
+
+        let mut flipped = b"#?RADIANCE\n\n+Y 1 +X 1\n".to_vec();
+        flipped.extend([128, 128, 128, 128]);
+        assert!(decode_radiance_hdr(&flipped).is_err());
+
+        let mut xyz = b"#?RADIANCE\nFORMAT=32-bit_rle_xyze\n\n-Y 1 +X 1\n".to_vec();
+        xyz.extend([128, 128, 128, 128]);
+        assert!(decode_radiance_hdr(&xyz).is_err());
     }
 }
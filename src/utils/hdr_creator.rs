@@ -0,0 +1,282 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use crate::scene::scene::Scene;
+
+use super::{file_loader::output_path, vec3::Color};
+
+/// File format of the rendered images, only PNG clamps and quantizes the colors
+#[derive(Clone, Copy)]
+pub enum OutputFormat {
+    Png,
+    OpenExr,
+    RadianceHdr,
+    Pfm,
+}
+
+impl OutputFormat {
+    /// Format belonging to a file extension, e.g. `exr`
+    pub fn parse(extension: &str) -> Option<OutputFormat> {
+        match extension.to_ascii_lowercase().as_str() {
+            "png" => Some(OutputFormat::Png),
+            "exr" => Some(OutputFormat::OpenExr),
+            "hdr" => Some(OutputFormat::RadianceHdr),
+            "pfm" => Some(OutputFormat::Pfm),
+            _ => None,
+        }
+    }
+
+    /// Chosen by the extension of the file name, PNG for unknown extensions
+    pub fn from_file_name(file_name: &str) -> OutputFormat {
+        Path::new(file_name)
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .and_then(OutputFormat::parse)
+            .unwrap_or(OutputFormat::Png)
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Png => "png",
+            OutputFormat::OpenExr => "exr",
+            OutputFormat::RadianceHdr => "hdr",
+            OutputFormat::Pfm => "pfm",
+        }
+    }
+}
+
+fn create_file(file_name: &str) -> BufWriter<File> {
+    let file = File::create(output_path(file_name)).expect("Failed to create file");
+    BufWriter::new(file)
+}
+
+/// Uncompressed scanline OpenEXR image with 32 bit float channels
+pub(crate) fn create_exr_at_path(colors: &[Color], scene: &Scene, file_name: &str) {
+    let width = scene.camera.resolution_horizontal;
+    let height = scene.camera.resolution_vertical;
+
+    let mut header = vec![];
+    header.extend(0x0131_2f76u32.to_le_bytes());
+    header.extend(2u32.to_le_bytes());
+
+    let mut add_attribute = |name: &str, attribute_type: &str, value: &[u8]| {
+        header.extend(name.as_bytes());
+        header.push(0);
+        header.extend(attribute_type.as_bytes());
+        header.push(0);
+        header.extend((value.len() as i32).to_le_bytes());
+        header.extend(value);
+    };
+
+    // Channels have to be sorted by name
+    let mut channels = vec![];
+    for channel_name in ["B", "G", "R"] {
+        channels.extend(channel_name.as_bytes());
+        channels.push(0);
+        channels.extend(2i32.to_le_bytes()); // FLOAT
+        channels.extend([0u8; 4]); // pLinear and reserved
+        channels.extend(1i32.to_le_bytes()); // xSampling
+        channels.extend(1i32.to_le_bytes()); // ySampling
+    }
+    channels.push(0);
+
+    let mut window = vec![];
+    for value in [0, 0, width as i32 - 1, height as i32 - 1] {
+        window.extend(value.to_le_bytes());
+    }
+
+    add_attribute("channels", "chlist", &channels);
+    add_attribute("compression", "compression", &[0]);
+    add_attribute("dataWindow", "box2i", &window);
+    add_attribute("displayWindow", "box2i", &window);
+    add_attribute("lineOrder", "lineOrder", &[0]);
+    add_attribute("pixelAspectRatio", "float", &1.0f32.to_le_bytes());
+    add_attribute("screenWindowCenter", "v2f", &[0u8; 8]);
+    add_attribute("screenWindowWidth", "float", &1.0f32.to_le_bytes());
+    header.push(0);
+
+    // Every scanline is a chunk of its y coordinate, the data size and the channels one after another
+    let chunk_size = 8 + width * 3 * 4;
+    let first_chunk = header.len() + height * 8;
+
+    let mut writer = create_file(file_name);
+    writer
+        .write_all(&header)
+        .expect("Failed to write EXR header");
+    for row in 0..height {
+        let offset = (first_chunk + row * chunk_size) as u64;
+        writer
+            .write_all(&offset.to_le_bytes())
+            .expect("Failed to write EXR offset table");
+    }
+
+    for (row, row_colors) in colors.chunks_exact(width).enumerate() {
+        let mut chunk = Vec::with_capacity(chunk_size);
+        chunk.extend((row as i32).to_le_bytes());
+        chunk.extend(((width * 3 * 4) as i32).to_le_bytes());
+        for channel in [Color::b, Color::g, Color::r] {
+            for color in row_colors {
+                chunk.extend(channel(color).to_le_bytes());
+            }
+        }
+        writer
+            .write_all(&chunk)
+            .expect("Failed to write EXR pixel data");
+    }
+
+    writer.flush().expect("Failed to finish writing EXR");
+}
+
+/// Radiance RGBE image, the scanlines are written without run length encoding
+pub(crate) fn create_radiance_hdr_at_path(colors: &[Color], scene: &Scene, file_name: &str) {
+    let mut writer = create_file(file_name);
+    write!(
+        writer,
+        "#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y {} +X {}\n",
+        scene.camera.resolution_vertical, scene.camera.resolution_horizontal
+    )
+    .expect("Failed to write HDR header");
+
+    for color in colors {
+        writer
+            .write_all(&to_rgbe(color))
+            .expect("Failed to write HDR pixel data");
+    }
+
+    writer.flush().expect("Failed to finish writing HDR");
+}
+
+// Shared exponent of the largest component, negative components can't be stored
+fn to_rgbe(color: &Color) -> [u8; 4] {
+    let components = [color.r().max(0.0), color.g().max(0.0), color.b().max(0.0)];
+    let maximum = components[0].max(components[1]).max(components[2]);
+    if maximum < 1e-32 || !maximum.is_finite() {
+        return [0; 4];
+    }
+
+    // maximum = mantissa * 2^exponent with the mantissa in [0.5, 1)
+    let exponent = maximum.log2().floor() as i32 + 1;
+    let scale = 256.0 / 2f32.powi(exponent);
+    [
+        (components[0] * scale).min(255.0) as u8,
+        (components[1] * scale).min(255.0) as u8,
+        (components[2] * scale).min(255.0) as u8,
+        (exponent + 128).clamp(0, 255) as u8,
+    ]
+}
+
+/// Portable float map, the rows are stored from the bottom to the top
+pub(crate) fn create_pfm_at_path(colors: &[Color], scene: &Scene, file_name: &str) {
+    let width = scene.camera.resolution_horizontal;
+
+    let mut writer = create_file(file_name);
+    // A negative scale marks little endian data
+    write!(
+        writer,
+        "PF\n{} {}\n-1.0\n",
+        width, scene.camera.resolution_vertical
+    )
+    .expect("Failed to write PFM header");
+
+    for row_colors in colors.chunks_exact(width).rev() {
+        for color in row_colors {
+            for component in [color.r(), color.g(), color.b()] {
+                writer
+                    .write_all(&component.to_le_bytes())
+                    .expect("Failed to write PFM pixel data");
+            }
+        }
+    }
+
+    writer.flush().expect("Failed to finish writing PFM");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Inverse of to_rgbe like a Radiance reader, with the mantissas in the middle of their step
+    fn from_rgbe([r, g, b, exponent]: [u8; 4]) -> [f32; 3] {
+        let factor = 2f32.powi(exponent as i32 - 136);
+        [r, g, b].map(|mantissa| (mantissa as f32 + 0.5) * factor)
+    }
+
+    #[test]
+    fn rgbe_shares_the_exponent_of_the_largest_component() {
+        assert_eq!(
+            to_rgbe(&Color::from_values(1.0, 0.5, 0.25)),
+            [128, 64, 32, 129]
+        );
+        assert_eq!(
+            to_rgbe(&Color::from_values(0.0, 0.0, 1000.0)),
+            [0, 0, 250, 138]
+        );
+    }
+
+    #[test]
+    fn rgbe_round_trip() {
+        for color in [
+            Color::from_values(0.3, 0.6, 0.9),
+            Color::from_values(12.5, 0.01, 3.0),
+            Color::from_values(1e-5, 2e-5, 0.0),
+        ] {
+            let decoded = from_rgbe(to_rgbe(&color));
+            let maximum = color.r().max(color.g()).max(color.b());
+            for (value, expected) in decoded.iter().zip([color.r(), color.g(), color.b()]) {
+                assert!((value - expected).abs() <= maximum / 128.0);
+            }
+        }
+    }
+
+    #[test]
+    fn rgbe_of_values_which_cant_be_stored() {
+        assert_eq!(to_rgbe(&Color::new()), [0; 4]);
+        assert_eq!(to_rgbe(&Color::from_values(-1.0, -2.0, -3.0)), [0; 4]);
+        assert_eq!(
+            to_rgbe(&Color::from_values(f32::INFINITY, 1.0, 1.0)),
+            [0; 4]
+        );
+        // Negative and NaN components are stored as zero next to positive ones
+        assert_eq!(
+            to_rgbe(&Color::from_values(f32::NAN, 1.0, 1.0)),
+            [0, 128, 128, 129]
+        );
+        assert_eq!(
+            to_rgbe(&Color::from_values(-1.0, 1.0, 0.0)),
+            [0, 128, 0, 129]
+        );
+    }
+
+    #[test]
+    fn output_format_by_extension() {
+        assert!(matches!(
+            OutputFormat::parse("EXR"),
+            Some(OutputFormat::OpenExr)
+        ));
+        assert!(OutputFormat::parse("jpg").is_none());
+        assert!(matches!(
+            OutputFormat::from_file_name("sky.Hdr"),
+            OutputFormat::RadianceHdr
+        ));
+        assert!(matches!(
+            OutputFormat::from_file_name("image.pfm"),
+            OutputFormat::Pfm
+        ));
+        assert!(matches!(
+            OutputFormat::from_file_name("no_extension"),
+            OutputFormat::Png
+        ));
+        for format in [
+            OutputFormat::Png,
+            OutputFormat::OpenExr,
+            OutputFormat::RadianceHdr,
+            OutputFormat::Pfm,
+        ] {
+            assert_eq!(
+                OutputFormat::parse(format.extension()).map(|parsed| parsed.extension()),
+                Some(format.extension())
+            );
+        }
+    }
+}
@@ -122,6 +122,41 @@ impl Mat4 {
         mat
     }
 
+    pub fn create_rotation_matrix_z(angle: &f32) -> Mat4 {
+        let mut mat = Mat4::identity();
+        let cos_theta = angle.cos();
+        let sin_theta = angle.sin();
+
+        mat.matrix[0] = cos_theta;
+        mat.matrix[1] = -sin_theta;
+        mat.matrix[4] = sin_theta;
+        mat.matrix[5] = cos_theta;
+
+        mat
+    }
+
+    /// Rotation around an arbitrary axis (Rodrigues' rotation formula)
+    pub fn create_rotation_matrix(axis: &Vec3, angle: &f32) -> Mat4 {
+        let mut mat = Mat4::identity();
+        let axis = axis.unit_vector();
+        let (x, y, z) = (axis.x(), axis.y(), axis.z());
+        let cos_theta = angle.cos();
+        let sin_theta = angle.sin();
+        let one_minus_cos = 1.0 - cos_theta;
+
+        mat.matrix[0] = cos_theta + x * x * one_minus_cos;
+        mat.matrix[1] = x * y * one_minus_cos - z * sin_theta;
+        mat.matrix[2] = x * z * one_minus_cos + y * sin_theta;
+        mat.matrix[4] = y * x * one_minus_cos + z * sin_theta;
+        mat.matrix[5] = cos_theta + y * y * one_minus_cos;
+        mat.matrix[6] = y * z * one_minus_cos - x * sin_theta;
+        mat.matrix[8] = z * x * one_minus_cos - y * sin_theta;
+        mat.matrix[9] = z * y * one_minus_cos + x * sin_theta;
+        mat.matrix[10] = cos_theta + z * z * one_minus_cos;
+
+        mat
+    }
+
     pub fn create_translation_matrix(translation: &Vec3) -> Mat4 {
         let mut mat = Mat4::identity();
         mat.matrix[3] = translation.x();
@@ -130,62 +165,105 @@ impl Mat4 {
         mat
     }
 
-    pub fn create_world_to_object_transformation_matrix(
-        transform_operations: &Vec<TransformationEnum>,
-    ) -> Mat4 {
-        let mut final_matrix = Mat4::identity();
+    /// Determinant of the upper 3x3 part, i.e. the change of volume of the linear transformation
+    pub fn determinant_3x3(&self) -> f32 {
+        let m = &self.matrix;
+        m[0] * (m[5] * m[10] - m[6] * m[9]) - m[1] * (m[4] * m[10] - m[6] * m[8])
+            + m[2] * (m[4] * m[9] - m[5] * m[8])
+    }
 
-        for operation in transform_operations.iter().rev() {
-            match operation {
-                TransformationEnum::Scale(scale) => {
-                    let scale_matrix = Mat4::create_scaling_matrix(&(&1.0/scale));
-                    final_matrix = &final_matrix * &scale_matrix;
-                }
-                TransformationEnum::Translate(translation_vector) => {
-                    let translation_matrix = Mat4::create_translation_matrix(&-translation_vector);
-                    final_matrix = &final_matrix * &translation_matrix;
+    /// General inverse by Gauss-Jordan elimination with partial pivoting,
+    /// `None` if the matrix is singular
+    pub fn inverse(&self) -> Option<Mat4> {
+        // Computed in double precision, as scenes may chain many operations
+        let mut left: [f64; 16] = self.matrix.map(|value| value as f64);
+        let mut right: [f64; 16] = Mat4::identity().matrix.map(|value| value as f64);
+
+        for column in 0..4 {
+            let pivot_row = (column..4)
+                .max_by(|&a, &b| {
+                    left[a * 4 + column]
+                        .abs()
+                        .total_cmp(&left[b * 4 + column].abs())
+                })
+                .unwrap();
+            if left[pivot_row * 4 + column].abs() < 1e-12 {
+                return None;
+            }
+
+            if pivot_row != column {
+                for j in 0..4 {
+                    left.swap(pivot_row * 4 + j, column * 4 + j);
+                    right.swap(pivot_row * 4 + j, column * 4 + j);
                 }
-                TransformationEnum::RotateX { angle } => {
-                    let rotation_matrix = Mat4::create_rotation_matrix_x(&-angle.to_radians());
-                    final_matrix = &final_matrix * &rotation_matrix;
+            }
+
+            let pivot = left[column * 4 + column];
+            for j in 0..4 {
+                left[column * 4 + j] /= pivot;
+                right[column * 4 + j] /= pivot;
+            }
+
+            for row in 0..4 {
+                if row == column {
+                    continue;
                 }
-                TransformationEnum::RotateY { angle } => {
-                    let rotation_matrix = Mat4::create_rotation_matrix_y(&-angle.to_radians());
-                    final_matrix = &final_matrix * &rotation_matrix;
+                let factor = left[row * 4 + column];
+                for j in 0..4 {
+                    left[row * 4 + j] -= factor * left[column * 4 + j];
+                    right[row * 4 + j] -= factor * right[column * 4 + j];
                 }
             }
         }
 
-        final_matrix
+        Some(Mat4 {
+            matrix: right.map(|value| value as f32),
+        })
     }
 
-    pub fn create_normal_matrix_of_object_to_world_space(
-        transform_operations: &Vec<TransformationEnum>,
+    pub fn create_object_to_world_transformation_matrix(
+        transform_operations: &[TransformationEnum],
     ) -> Mat4 {
         let mut final_matrix = Mat4::identity();
 
         for operation in transform_operations {
-            match operation {
-                TransformationEnum::Scale(scale) => {
-                    let scale_matrix = Mat4::create_scaling_matrix(&(&1.0/scale));
-                    final_matrix = &final_matrix * &scale_matrix;
-                }
-                TransformationEnum::Translate(_) => {
-                    continue;
+            let operation_matrix = match operation {
+                TransformationEnum::Scale(scale) => Mat4::create_scaling_matrix(scale),
+                TransformationEnum::Translate(translation_vector) => {
+                    Mat4::create_translation_matrix(translation_vector)
                 }
                 TransformationEnum::RotateX { angle } => {
-                    let rotation_matrix = Mat4::create_rotation_matrix_x(&angle.to_radians());
-                    final_matrix = &final_matrix * &rotation_matrix;
+                    Mat4::create_rotation_matrix_x(&angle.to_radians())
                 }
                 TransformationEnum::RotateY { angle } => {
-                    let rotation_matrix = Mat4::create_rotation_matrix_y(&angle.to_radians());
-                    final_matrix = &final_matrix * &rotation_matrix;
+                    Mat4::create_rotation_matrix_y(&angle.to_radians())
                 }
-            }
+                TransformationEnum::RotateZ { angle } => {
+                    Mat4::create_rotation_matrix_z(&angle.to_radians())
+                }
+                TransformationEnum::Rotate { angle, x, y, z } => Mat4::create_rotation_matrix(
+                    &Vec3::from_values(*x, *y, *z),
+                    &angle.to_radians(),
+                ),
+                TransformationEnum::Matrix(matrix) => *matrix,
+            };
+            final_matrix = &final_matrix * &operation_matrix;
         }
 
         final_matrix
     }
+
+    /// `None` if the object to world matrix is singular, e.g. because of a zero scale
+    pub fn create_world_to_object_transformation_matrix(
+        object_to_world_matrix: &Mat4,
+    ) -> Option<Mat4> {
+        object_to_world_matrix.inverse()
+    }
+
+    /// Inverse transpose of the object to world matrix, only the upper 3x3 part is used for normals
+    pub fn create_normal_matrix_of_object_to_world_space(world_to_object_matrix: &Mat4) -> Mat4 {
+        world_to_object_matrix.transpose()
+    }
 }
 
 impl Add<&Mat4> for &Mat4 {
@@ -239,3 +317,49 @@ impl Mul<&f32> for &Mat4 {
         result
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_identity(matrix: &Mat4) {
+        let identity = Mat4::identity();
+        for (value, expected) in matrix.matrix.iter().zip(identity.matrix) {
+            assert!((value - expected).abs() < 1e-5, "{:?}", matrix);
+        }
+    }
+
+    #[test]
+    fn inverse_of_chained_transformations() {
+        let matrix = Mat4::create_object_to_world_transformation_matrix(&[
+            TransformationEnum::Translate(Vec3::from_values(1.0, -2.0, 3.0)),
+            TransformationEnum::RotateX { angle: 30.0 },
+            TransformationEnum::RotateY { angle: -70.0 },
+            TransformationEnum::Scale(Vec3::from_values(2.0, 0.5, 4.0)),
+        ]);
+        let inverse = matrix.inverse().unwrap();
+
+        assert_identity(&(&matrix * &inverse));
+        assert_identity(&(&inverse * &matrix));
+    }
+
+    #[test]
+    fn inverse_needs_pivoting() {
+        // Zero on the diagonal, solvable only by swapping rows
+        let matrix = Mat4 {
+            matrix: [
+                0.0, 1.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 2.0, 0.0, 0.0, 1.0, 0.0,
+            ],
+        };
+
+        assert_identity(&(&matrix * &matrix.inverse().unwrap()));
+    }
+
+    #[test]
+    fn singular_matrix_has_no_inverse() {
+        let flattened = Mat4::create_scaling_matrix(&Vec3::from_values(1.0, 0.0, 1.0));
+
+        assert!(flattened.inverse().is_none());
+        assert!(Mat4::new().inverse().is_none());
+    }
+}
@@ -1,7 +1,11 @@
+pub mod aov;
+pub mod cli_arguments;
 pub mod color_utility;
 pub mod deserialization_helpers;
 pub mod file_loader;
+pub mod hdr_creator;
 pub mod png_creator;
+pub mod tone_mapping;
 pub mod vec3;
 pub mod obj_parser;
 pub mod mat4;
\ No newline at end of file
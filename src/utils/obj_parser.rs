@@ -30,7 +30,7 @@ impl OBJParser {
     pub fn extract_data(&mut self, data: &str) -> (Vec<Vec3>, Vec<Vec3>, Vec<usize>, Vec<Vec3>) {
         let lines = data.split("\n");
         for line in lines {
-            let elements: Vec<&str> = line.trim().split_whitespace().collect();
+            let elements: Vec<&str> = line.split_whitespace().collect();
 
             if elements.is_empty() {
                 continue;
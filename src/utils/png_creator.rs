@@ -1,16 +1,15 @@
 use png::{BitDepth, ColorType, Encoder};
 use std::fs::File;
 use std::io::BufWriter;
-use std::path::Path;
 
 use crate::scene::scene::Scene;
 
-pub(crate) fn create_png_at_path(png_data: &Vec<u8>, scene: &Scene) {
-    let output_directory = Path::new("output_files");
-    let target_location = output_directory.join(&scene.output_file);
-    let file = File::create(target_location).expect("Failed to create file");
+use super::file_loader::output_path;
 
-    let ref mut writer = BufWriter::new(file);
+pub(crate) fn create_png_at_path(png_data: &[u8], scene: &Scene, file_name: &str) {
+    let file = File::create(output_path(file_name)).expect("Failed to create file");
+
+    let writer = &mut BufWriter::new(file);
     let mut encoder = Encoder::new(
         writer,
         scene.camera.resolution_horizontal as u32,
@@ -31,7 +30,7 @@ pub(crate) fn create_png_at_path(png_data: &Vec<u8>, scene: &Scene) {
     let mut writer = encoder.write_header().expect("Failed to write PNG header");
 
     writer
-        .write_image_data(&png_data)
+        .write_image_data(png_data)
         .expect("Failed to write pixel data");
 
     writer.finish().expect("Failed to finish writing PNG");
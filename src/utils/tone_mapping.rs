@@ -0,0 +1,147 @@
+use serde::Deserialize;
+
+use super::vec3::Color;
+
+/// Compresses the unbounded colors of the framebuffer into the displayable range
+#[derive(Deserialize, Clone, Copy)]
+pub enum ToneMapOperator {
+    // Colors above one are clipped
+    #[serde(rename = "clamp")]
+    Clamp,
+    #[serde(rename = "reinhard")]
+    Reinhard,
+    // Curve fitted to the ACES filmic reference rendering transform by Krzysztof Narkowicz
+    #[serde(rename = "aces")]
+    Aces,
+    // Filmic curve of Uncharted 2 by John Hable
+    #[serde(rename = "hable")]
+    Hable,
+}
+
+impl ToneMapOperator {
+    pub fn parse(name: &str) -> Option<ToneMapOperator> {
+        match name {
+            "clamp" => Some(ToneMapOperator::Clamp),
+            "reinhard" => Some(ToneMapOperator::Reinhard),
+            "aces" => Some(ToneMapOperator::Aces),
+            "hable" => Some(ToneMapOperator::Hable),
+            _ => None,
+        }
+    }
+
+    fn map(&self, value: f32) -> f32 {
+        let value = value.max(0.0);
+        match self {
+            ToneMapOperator::Clamp => value.min(1.0),
+            ToneMapOperator::Reinhard => value / (1.0 + value),
+            ToneMapOperator::Aces => ((value * (2.51 * value + 0.03))
+                / (value * (2.43 * value + 0.59) + 0.14))
+                .clamp(0.0, 1.0),
+            // The white point of 11.2 is mapped to one after doubling the exposure
+            ToneMapOperator::Hable => {
+                (hable_curve(2.0 * value) / hable_curve(HABLE_WHITE_POINT)).min(1.0)
+            }
+        }
+    }
+}
+
+const HABLE_WHITE_POINT: f32 = 11.2;
+
+fn hable_curve(x: f32) -> f32 {
+    let (a, b, c, d, e, f) = (0.15, 0.50, 0.10, 0.20, 0.02, 0.30);
+    ((x * (a * x + c * b) + d * e) / (x * (a * x + b) + d * f)) - e / f
+}
+
+fn default_operator() -> ToneMapOperator {
+    ToneMapOperator::Clamp
+}
+
+/// Applied to the rendered image before it is quantized to 8 bit
+#[derive(Deserialize, Clone, Copy)]
+pub struct ToneMapping {
+    #[serde(rename = "@operator")]
+    #[serde(default = "default_operator")]
+    pub operator: ToneMapOperator,
+    // In stops, every stop doubles the brightness
+    #[serde(rename = "@exposure")]
+    #[serde(default)]
+    pub exposure: f32,
+}
+
+impl Default for ToneMapping {
+    fn default() -> Self {
+        Self {
+            operator: default_operator(),
+            exposure: 0.0,
+        }
+    }
+}
+
+impl ToneMapping {
+    /// Maps each channel separately, the result lies in [0, 1]
+    pub fn apply(&self, color: &Color) -> Color {
+        let scale = 2f32.powf(self.exposure);
+        Color::from_values(
+            self.operator.map(color.r() * scale),
+            self.operator.map(color.g() * scale),
+            self.operator.map(color.b() * scale),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OPERATORS: [ToneMapOperator; 4] = [
+        ToneMapOperator::Clamp,
+        ToneMapOperator::Reinhard,
+        ToneMapOperator::Aces,
+        ToneMapOperator::Hable,
+    ];
+
+    #[test]
+    fn operators_are_monotonic_and_bounded() {
+        for operator in OPERATORS {
+            assert!(operator.map(0.0).abs() < 1e-6);
+            assert_eq!(operator.map(-1.0), operator.map(0.0));
+            assert_eq!(operator.map(f32::NAN), operator.map(0.0));
+
+            let mut previous = operator.map(0.0);
+            for step in 1..=1000 {
+                let mapped = operator.map(step as f32 * 0.05);
+                assert!(mapped >= previous && mapped <= 1.0);
+                previous = mapped;
+            }
+        }
+    }
+
+    #[test]
+    fn operator_reference_values() {
+        assert_eq!(ToneMapOperator::Clamp.map(0.5), 0.5);
+        assert_eq!(ToneMapOperator::Reinhard.map(1.0), 0.5);
+        assert!((ToneMapOperator::Aces.map(1.0) - 0.803_797).abs() < 1e-5);
+        // Half of the white point, as the exposure is doubled
+        assert!((ToneMapOperator::Hable.map(HABLE_WHITE_POINT / 2.0) - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn exposure_is_applied_before_the_operator() {
+        let tone_mapping = ToneMapping {
+            operator: ToneMapOperator::Clamp,
+            exposure: 1.0,
+        };
+        let mapped = tone_mapping.apply(&Color::from_values(0.25, 0.5, 2.0));
+
+        assert_eq!((mapped.r(), mapped.g(), mapped.b()), (0.5, 1.0, 1.0));
+    }
+
+    #[test]
+    fn parse_operator_names() {
+        for (name, operator) in ["clamp", "reinhard", "aces", "hable"].iter().zip(OPERATORS) {
+            let parsed = ToneMapOperator::parse(name).unwrap();
+            assert_eq!(parsed.map(2.0), operator.map(2.0));
+        }
+        assert!(ToneMapOperator::parse("filmic").is_none());
+    }
+}